@@ -0,0 +1,35 @@
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Wraps diagnostic text in ANSI escape codes when enabled.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Style {
+    enabled: bool,
+}
+
+impl Style {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    pub fn error(&self, text: &str) -> String {
+        self.paint(RED, text)
+    }
+
+    pub fn warning(&self, text: &str) -> String {
+        self.paint(YELLOW, text)
+    }
+
+    pub fn dim(&self, text: &str) -> String {
+        self.paint(DIM, text)
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        match self.enabled {
+            true => format!("{code}{text}{RESET}"),
+            false => text.into(),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use crate::rules::{ParseFunctionKind, Precedence};
+use crate::scanner::{Scanner, Token, TokenKind};
+
+/// A node of the printed tree. The compiler emits bytecode as it parses, so
+/// this is only ever built to be shown.
+enum Node {
+    Leaf(String),
+    /// Printed on one line, e.g. `(add 1 (multiply 2 3))`
+    Expression(Vec<Node>),
+    /// A head printed like an expression, then each child on its own line
+    Tree(Vec<Node>, Vec<Node>),
+}
+
+impl Node {
+    fn leaf(text: &str) -> Node {
+        Node::Leaf(text.into())
+    }
+
+    fn render(&self, indent: usize, output: &mut String) {
+        match self {
+            Node::Leaf(text) => output.push_str(text),
+            Node::Expression(items) => Self::render_head(items, indent, output),
+            Node::Tree(head, children) => {
+                Self::render_head(head, indent, output);
+                output.pop();
+                for child in children {
+                    output.push('\n');
+                    output.push_str(&" ".repeat(indent + 2));
+                    child.render(indent + 2, output);
+                }
+                output.push(')');
+            }
+        }
+    }
+
+    fn render_head(items: &[Node], indent: usize, output: &mut String) {
+        output.push('(');
+        for (index, item) in items.iter().enumerate() {
+            if index > 0 {
+                output.push(' ');
+            }
+            item.render(indent, output);
+        }
+        output.push(')');
+    }
+}
+
+/// Print the structure of a recipe as nested parentheses, e.g. `taste 1 add
+/// 2 multiply 3` as `(taste (add 1 (multiply 2 3)))`.
+///
+/// This is a second walk over the tokens with the compiler's parse rules, and
+/// expects a recipe that has already compiled. Anything it doesn't follow is
+/// shown as a bare token rather than reported.
+pub fn dump_ast(source: &str) -> String {
+    let mut source = source.to_owned();
+    source.push('\0');
+    let mut printer = Printer::new(&source);
+    let mut output = String::new();
+    printer.recipe().render(0, &mut output);
+    output
+}
+
+struct Printer<'src> {
+    scanner: Scanner<'src>,
+    current: Token<'src>,
+    previous: Token<'src>,
+}
+
+impl<'src> Printer<'src> {
+    fn new(source: &'src str) -> Self {
+        let mut scanner = Scanner::new(source);
+        let current = scanner.scan_token();
+        Self {
+            scanner,
+            current,
+            previous: current,
+        }
+    }
+
+    fn recipe(&mut self) -> Node {
+        let mut sections = Vec::new();
+        self.r#match(TokenKind::Recipe);
+        while self.current.kind == TokenKind::Ident && self.current.lexeme == "include" {
+            self.advance();
+            self.advance();
+            sections.push(Node::Expression(vec![
+                Node::leaf("include"),
+                Node::leaf(self.previous.lexeme),
+            ]));
+        }
+        if self.r#match(TokenKind::AboutHeader) {
+            let mut entries = Vec::new();
+            while self.r#match(TokenKind::AboutEntry) {
+                if let Some((key, value)) = self.previous.lexeme.split_once(':') {
+                    entries.push(Node::Expression(vec![
+                        Node::leaf(key.trim()),
+                        Node::Leaf(format!("{:?}", value.trim())),
+                    ]));
+                }
+            }
+            sections.push(Node::Tree(vec![Node::leaf("about")], entries));
+        }
+        if self.r#match(TokenKind::IngredientsHeader) {
+            let mut ingredients = Vec::new();
+            while self.r#match(TokenKind::Var) {
+                ingredients.push(self.ingredient());
+            }
+            sections.push(Node::Tree(vec![Node::leaf("ingredients")], ingredients));
+        }
+        if self.r#match(TokenKind::UtensilsHeader) {
+            let mut utensils = Vec::new();
+            while self.r#match(TokenKind::FunIdent) {
+                utensils.push(self.utensil());
+            }
+            sections.push(Node::Tree(vec![Node::leaf("utensils")], utensils));
+        }
+        if self.r#match(TokenKind::StepsHeader) {
+            sections.push(Node::Tree(vec![Node::leaf("steps")], self.block()));
+        }
+        Node::Tree(vec![Node::leaf("recipe")], sections)
+    }
+
+    fn ingredient(&mut self) -> Node {
+        let quantity = match self.r#match(TokenKind::Number) {
+            true => {
+                let quantity = Node::leaf(self.previous.lexeme);
+                self.r#match(TokenKind::Ident);
+                Some(quantity)
+            }
+            false => None,
+        };
+        self.advance();
+        let mut items = vec![Node::leaf("set"), Node::leaf(self.previous.lexeme)];
+        if let Some(quantity) = quantity {
+            items.push(quantity);
+        } else if self.r#match(TokenKind::Equal) {
+            items.push(self.expression());
+        }
+        Node::Expression(items)
+    }
+
+    fn utensil(&mut self) -> Node {
+        let mut head = vec![Node::leaf("utensil"), Node::leaf(self.previous.lexeme)];
+        if self.r#match(TokenKind::With) {
+            let mut parameters = vec![Node::leaf("with")];
+            loop {
+                self.advance();
+                parameters.push(Node::leaf(self.previous.lexeme));
+                if !(self.r#match(TokenKind::Comma) || self.r#match(TokenKind::ParameterAnd)) {
+                    break;
+                }
+            }
+            head.push(Node::Expression(parameters));
+        }
+        Node::Tree(head, self.block())
+    }
+
+    fn block(&mut self) -> Vec<Node> {
+        let mut statements = Vec::new();
+        if !self.r#match(TokenKind::Step) {
+            return statements;
+        }
+        loop {
+            if self.r#match(TokenKind::RightBrace) {
+                break;
+            }
+            if self.r#match(TokenKind::Else) {
+                let otherwise = Node::Tree(vec![Node::leaf("otherwise")], self.block());
+                // Shown inside the 'check' it belongs to
+                match statements.last_mut() {
+                    Some(Node::Tree(head, children)) if is_check(head) => children.push(otherwise),
+                    _ => statements.push(otherwise),
+                }
+            } else {
+                statements.push(self.statement());
+            }
+            if !self.r#match(TokenKind::Step) {
+                break;
+            }
+        }
+        statements
+    }
+
+    fn statement(&mut self) -> Node {
+        match self.current.kind {
+            TokenKind::Print
+            | TokenKind::DebugPrint
+            | TokenKind::PrettyPrint
+            | TokenKind::Return => {
+                self.advance();
+                let keyword = Node::leaf(self.previous.lexeme);
+                Node::Expression(vec![keyword, self.expression()])
+            }
+            TokenKind::If => {
+                self.advance();
+                let head = vec![Node::leaf("check"), self.expression()];
+                Node::Tree(head, self.block())
+            }
+            TokenKind::While => {
+                self.advance();
+                let mut head = vec![Node::leaf(self.previous.lexeme), self.expression()];
+                if self.r#match(TokenKind::Then) {
+                    head.push(Node::Expression(vec![
+                        Node::leaf("then"),
+                        self.expression(),
+                    ]));
+                }
+                Node::Tree(head, self.block())
+            }
+            TokenKind::Each => {
+                self.advance();
+                self.advance();
+                let name = Node::leaf(self.previous.lexeme);
+                self.r#match(TokenKind::In);
+                let head = vec![Node::leaf("each"), name, self.expression()];
+                Node::Tree(head, self.block())
+            }
+            TokenKind::Repeat => {
+                self.advance();
+                let head = vec![Node::leaf("repeat"), self.expression()];
+                self.r#match(TokenKind::Times);
+                Node::Tree(head, self.block())
+            }
+            _ => self.expression(),
+        }
+    }
+
+    fn expression(&mut self) -> Node {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Node {
+        let can_assign = precedence <= Precedence::Assignment && self.r#match(TokenKind::Var);
+        let prefix_rule = Precedence::get_rule(self.current.kind).prefix;
+        self.advance();
+        let mut node = self.prefix(prefix_rule, can_assign);
+        while precedence <= Precedence::get_rule(self.current.kind).precedence {
+            self.advance();
+            let rule = Precedence::get_rule(self.previous.kind);
+            node = self.infix(rule.infix, rule.precedence, node);
+        }
+        node
+    }
+
+    fn prefix(&mut self, kind: ParseFunctionKind, can_assign: bool) -> Node {
+        let token = self.previous;
+        match kind {
+            ParseFunctionKind::Grouping => {
+                let node = self.expression();
+                if token.kind == TokenKind::LeftParen {
+                    self.r#match(TokenKind::RightParen);
+                }
+                node
+            }
+            ParseFunctionKind::Unary => {
+                let operand = self.parse_precedence(Precedence::Unary);
+                Node::Expression(vec![Node::leaf(token.lexeme), operand])
+            }
+            ParseFunctionKind::Variable if can_assign && self.r#match(TokenKind::Equal) => {
+                let value = self.expression();
+                Node::Expression(vec![Node::leaf("set"), Node::leaf(token.lexeme), value])
+            }
+            ParseFunctionKind::List => {
+                let mut items = vec![Node::leaf("list")];
+                while !self.r#match(TokenKind::RightBracket) {
+                    items.push(self.expression());
+                    self.r#match(TokenKind::Comma);
+                    if self.current.kind == TokenKind::Eof {
+                        break;
+                    }
+                }
+                Node::Expression(items)
+            }
+            _ => Node::leaf(token.lexeme),
+        }
+    }
+
+    fn infix(&mut self, kind: ParseFunctionKind, precedence: Precedence, left: Node) -> Node {
+        let token = self.previous;
+        match kind {
+            ParseFunctionKind::Binary => {
+                let right = self.parse_precedence(precedence.next());
+                Node::Expression(vec![Node::leaf(token.lexeme), left, right])
+            }
+            ParseFunctionKind::And => {
+                let right = self.parse_precedence(Precedence::And);
+                Node::Expression(vec![Node::leaf(token.lexeme), left, right])
+            }
+            ParseFunctionKind::Or => {
+                let right = self.parse_precedence(Precedence::Or);
+                Node::Expression(vec![Node::leaf(token.lexeme), left, right])
+            }
+            ParseFunctionKind::Call => {
+                let mut items = vec![Node::leaf("call"), left];
+                if token.kind == TokenKind::BareFunctionInvocation && !self.r#match(TokenKind::With)
+                {
+                    return Node::Expression(items);
+                }
+                loop {
+                    items.push(self.expression());
+                    if !(self.r#match(TokenKind::Comma) || self.r#match(TokenKind::ParameterAnd)) {
+                        break;
+                    }
+                }
+                Node::Expression(items)
+            }
+            ParseFunctionKind::Index => {
+                let index = self.expression();
+                self.r#match(TokenKind::RightBracket);
+                Node::Expression(vec![Node::leaf("index"), left, index])
+            }
+            _ => left,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current;
+        if self.current.kind != TokenKind::Eof {
+            self.current = self.scanner.scan_token();
+        }
+    }
+
+    fn r#match(&mut self, token_kind: TokenKind) -> bool {
+        if self.current.kind != token_kind {
+            return false;
+        }
+        self.advance();
+        true
+    }
+}
+
+fn is_check(head: &[Node]) -> bool {
+    matches!(head.first(), Some(Node::Leaf(keyword)) if keyword == "check")
+}
@@ -1,31 +1,59 @@
-use std::{fmt::Debug, mem::transmute};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    mem::transmute,
+};
 
-use crate::{common::CONSTANTS_MAX_COUNT, value::Value};
+use crate::{
+    common::{json_string, BYTECODE_VERSION, CONSTANTS_MAX_COUNT},
+    error::{ChefError, Diagnostic, InterpretResult},
+    native_functions::NATIVE_FUNCTIONS,
+    value::{Function, Value},
+};
 
-#[derive(Debug)]
+const BYTECODE_MAGIC: &[u8; 4] = b"chef";
+
+/// Bytecode instructions. The values are part of the serialized format, so
+/// never renumber a variant: add new ones at the end and bump
+/// `BYTECODE_VERSION` if a value has to change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 pub enum Opcode {
-    Return,
-    Negate,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Nil,
-    True,
-    False,
-    Not,
-    Equal,
-    Greater,
-    Less,
-    Print,
-    Pop,
-    GetLocal,
-    SetLocal,
-    Constant,
-    JumpIfFalse,
-    Jump,
-    Loop,
-    Call,
+    Return = 0,
+    Negate = 1,
+    Add = 2,
+    Subtract = 3,
+    Multiply = 4,
+    Divide = 5,
+    FloorDivide = 6,
+    Nil = 7,
+    True = 8,
+    False = 9,
+    Not = 10,
+    Equal = 11,
+    Greater = 12,
+    Less = 13,
+    Print = 14,
+    Pop = 15,
+    GetLocal = 16,
+    SetLocal = 17,
+    Constant = 18,
+    JumpIfFalse = 19,
+    Jump = 20,
+    Loop = 21,
+    Call = 22,
+    List = 23,
+    Index = 24,
+    Length = 25,
+    PopN = 26,
+    Native = 27,
+    Dup = 28,
+    /// Does nothing, a placeholder for code patched out after it was emitted.
+    Nop = 29,
+    DebugPrint = 30,
+    PrettyPrint = 31,
+    In = 32,
+    JumpIfTrue = 33,
 }
 
 #[derive(Debug)]
@@ -34,9 +62,21 @@ pub struct Code {
     pub lines: Vec<usize>,
     pub constants: [Value; CONSTANTS_MAX_COUNT],
     pub constants_count: usize,
+    // Index of each constant, so adding one needn't scan them all
+    constant_indices: HashMap<Value, u8>,
+    // Non-fatal diagnostics reported while compiling
+    pub warnings: Vec<Diagnostic>,
+    // `key: value` lines from the `About` section, in order
+    pub metadata: Vec<(String, String)>,
 }
 
 const ARRAY_REPEAT_VALUE: Value = Value::Nil;
+impl Default for Code {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Code {
     pub fn new() -> Self {
         Self {
@@ -44,118 +84,419 @@ impl Code {
             lines: Vec::new(),
             constants: [ARRAY_REPEAT_VALUE; CONSTANTS_MAX_COUNT],
             constants_count: 0,
+            constant_indices: HashMap::new(),
+            warnings: Vec::new(),
+            metadata: Vec::new(),
         }
     }
 
+    /// The value given for `key` in the recipe's `About` section.
+    pub fn metadata_value(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(known, _)| known == key)
+            .map(|(_, value)| value.as_str())
+    }
+
     pub fn write(&mut self, byte: u8, line: usize) {
         self.bytes.push(byte);
         self.lines.push(line);
     }
 
     pub fn add_constant(&mut self, value: Value) -> Result<u8, &'static str> {
-        for constant_index in 0..self.constants_count {
-            if value == self.constants[constant_index] {
-                return Ok(constant_index as u8);
-            }
+        // NaN never equals itself, so is never found
+        if let Some(&index) = self.constant_indices.get(&value) {
+            return Ok(index);
         }
         if self.constants_count == CONSTANTS_MAX_COUNT {
             return Err("Too many constants defined in scope.");
         }
+        let index = self.constants_count as u8;
+        self.constant_indices.insert(value.clone(), index);
         self.constants[self.constants_count] = value;
         self.constants_count += 1;
-        Ok((self.constants_count - 1) as u8)
+        Ok(index)
+    }
+
+    /// Index the constants afresh, after they were changed in place.
+    pub(crate) fn reindex_constants(&mut self) {
+        self.constant_indices.clear();
+        for (index, constant) in self.constants[..self.constants_count].iter().enumerate() {
+            self.constant_indices
+                .entry(constant.clone())
+                .or_insert(index as u8);
+        }
+    }
+
+    /// Forget the constants from `count` on, along with their index entries.
+    pub fn truncate_constants(&mut self, count: usize) {
+        for constant in &mut self.constants[count..self.constants_count] {
+            let constant = std::mem::replace(constant, ARRAY_REPEAT_VALUE);
+            self.constant_indices.remove(&constant);
+        }
+        self.constants_count = count;
+    }
+}
+
+impl Opcode {
+    /// One more than the highest opcode value.
+    pub const COUNT: u8 = 34;
+
+    /// The opcode with value `byte`, if there is one.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        // Values run from 0 to `COUNT` without gaps, so any byte below it is valid
+        (byte < Self::COUNT).then(|| unsafe { transmute::<u8, Opcode>(byte) })
+    }
+
+    /// How many operand bytes follow the opcode.
+    pub fn operand_count(self) -> usize {
+        match self {
+            Opcode::GetLocal | Opcode::SetLocal => 2,
+            Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::Jump | Opcode::Loop => 2,
+            Opcode::Constant | Opcode::Call | Opcode::List | Opcode::PopN | Opcode::Native => 1,
+            _ => 0,
+        }
+    }
+}
+
+// Catch a variant added without updating `Opcode::COUNT`
+const _: () = assert!(Opcode::JumpIfTrue as u8 + 1 == Opcode::COUNT);
+
+impl Code {
+    /// Write the bytecode, line table and constants in the `.chefc` format,
+    /// headed by `BYTECODE_VERSION`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut output = BYTECODE_MAGIC.to_vec();
+        output.push(BYTECODE_VERSION);
+        output.extend((self.bytes.len() as u32).to_le_bytes());
+        output.extend(&self.bytes);
+        for line in &self.lines {
+            output.extend((*line as u32).to_le_bytes());
+        }
+        output.extend((self.constants_count as u16).to_le_bytes());
+        for constant in &self.constants[..self.constants_count] {
+            write_constant(&mut output, constant);
+        }
+        output
+    }
+
+    /// Load bytecode written by `serialize`, rejecting any other version.
+    pub fn deserialize(input: &[u8]) -> InterpretResult<Self> {
+        let mut reader = Reader { input, position: 0 };
+        if reader.take(BYTECODE_MAGIC.len())? != BYTECODE_MAGIC {
+            return Err(ChefError::InvalidBytecode);
+        }
+        let version = reader.u8()?;
+        if version != BYTECODE_VERSION {
+            return Err(ChefError::BytecodeVersion(version, BYTECODE_VERSION));
+        }
+        let mut code = Code::new();
+        let length = reader.u32()? as usize;
+        code.bytes = reader.take(length)?.to_vec();
+        for _ in 0..length {
+            code.lines.push(reader.u32()? as usize);
+        }
+        code.constants_count = reader.u16()? as usize;
+        if code.constants_count > CONSTANTS_MAX_COUNT {
+            return Err(ChefError::InvalidBytecode);
+        }
+        for index in 0..code.constants_count {
+            code.constants[index] = reader.constant()?;
+        }
+        code.reindex_constants();
+        if reader.position != input.len() || !code.has_valid_instructions() {
+            return Err(ChefError::InvalidBytecode);
+        }
+        Ok(code)
+    }
+
+    fn has_valid_instructions(&self) -> bool {
+        let mut offset = 0;
+        while offset < self.bytes.len() {
+            let Some(operation) = Opcode::from_byte(self.bytes[offset]) else {
+                return false;
+            };
+            let operand_count = operation.operand_count();
+            let Some(operands) = self.bytes.get(offset + 1..offset + 1 + operand_count) else {
+                return false;
+            };
+            match operation {
+                Opcode::Constant if operands[0] as usize >= self.constants_count => return false,
+                Opcode::Native if operands[0] as usize >= NATIVE_FUNCTIONS.len() => {
+                    return false;
+                }
+                _ => {}
+            }
+            offset += 1 + operand_count;
+        }
+        true
+    }
+}
+
+fn write_constant(output: &mut Vec<u8>, constant: &Value) {
+    match constant {
+        Value::Nil => output.push(0),
+        Value::Number(number) => {
+            output.push(1);
+            output.extend(number.to_le_bytes());
+        }
+        Value::Boolean(boolean) => output.extend([2, *boolean as u8]),
+        Value::String(string) => {
+            output.push(3);
+            write_string(output, string);
+        }
+        Value::List(elements) => {
+            output.push(4);
+            output.extend((elements.len() as u32).to_le_bytes());
+            for element in elements {
+                write_constant(output, element);
+            }
+        }
+        Value::Function(function) => {
+            output.push(5);
+            write_string(output, &function.name);
+            output.push(function.arity);
+            output.extend((function.ip_start as u32).to_le_bytes());
+        }
+        Value::Unset(name) => {
+            output.push(6);
+            write_string(output, name);
+        }
+        Value::NativeFunction(_) => unreachable!("Natives are loaded by index, not as constants."),
     }
 }
 
+fn write_string(output: &mut Vec<u8>, string: &str) {
+    output.extend((string.len() as u32).to_le_bytes());
+    output.extend(string.as_bytes());
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> InterpretResult<&'a [u8]> {
+        let end = self.position.checked_add(count);
+        let bytes = end
+            .and_then(|end| self.input.get(self.position..end))
+            .ok_or(ChefError::InvalidBytecode)?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> InterpretResult<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> InterpretResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> InterpretResult<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> InterpretResult<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> InterpretResult<String> {
+        let length = self.u32()? as usize;
+        let bytes = self.take(length)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| ChefError::InvalidBytecode)
+    }
+
+    fn constant(&mut self) -> InterpretResult<Value> {
+        let value = match self.u8()? {
+            0 => Value::Nil,
+            1 => Value::Number(f64::from_le_bytes(self.array()?)),
+            2 => Value::Boolean(self.u8()? != 0),
+            3 => Value::String(self.string()?.into()),
+            4 => {
+                let length = self.u32()?;
+                let mut elements = Vec::new();
+                for _ in 0..length {
+                    elements.push(self.constant()?);
+                }
+                Value::List(elements)
+            }
+            5 => Value::Function(Function {
+                name: self.string()?,
+                arity: self.u8()?,
+                ip_start: self.u32()? as usize,
+            }),
+            6 => Value::Unset(self.string()?),
+            _ => return Err(ChefError::InvalidBytecode),
+        };
+        Ok(value)
+    }
+}
+
+/// A decoded instruction operand, shown as `name: value` in disassembly.
+pub enum Operand {
+    Number(usize),
+    Text(String),
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Number(number) => write!(f, "{number}"),
+            Operand::Text(text) => write!(f, "{text}"),
+        }
+    }
+}
+
+type Operands = Vec<(&'static str, Operand)>;
+
 #[allow(unused)]
 impl Code {
     pub fn disassemble(&self) {
         println!("====== Code ======");
         let mut offset = 0;
         while offset < self.bytes.len() {
-            offset = self.disassemble_instruction(offset)
+            let (instruction, next_offset) = self.disassemble_instruction(offset);
+            println!("{instruction}");
+            offset = next_offset;
         }
         println!();
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        let byte = self.bytes[offset];
-        let line = self.lines[offset];
-        if offset > 0 && line == self.lines[offset - 1] {
-            print!("{offset:0>4} {:>9}  ", "|");
-        } else {
-            print!("{offset:0>4} {line:>9}  ");
-        }
-        let operation: Opcode = unsafe { transmute(byte) };
-        match operation {
-            Opcode::Return => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Negate => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Add => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Subtract => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Multiply => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Divide => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Nil => self.disassemble_simple_instruction(operation, offset),
-            Opcode::True => self.disassemble_simple_instruction(operation, offset),
-            Opcode::False => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Not => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Equal => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Greater => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Less => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Print => self.disassemble_simple_instruction(operation, offset),
-            Opcode::Pop => self.disassemble_simple_instruction(operation, offset),
-            Opcode::GetLocal => self.disassemble_byte_instruction(operation, offset),
-            Opcode::SetLocal => self.disassemble_byte_instruction(operation, offset),
-            Opcode::Constant => self.disassemble_constant_instruction(operation, offset),
-            Opcode::JumpIfFalse => self.disassemble_jump_instruction(operation, offset),
-            Opcode::Jump => self.disassemble_jump_instruction(operation, offset),
-            Opcode::Loop => self.disassemble_jump_instruction(operation, offset),
-            Opcode::Call => self.disassemble_call_instruction(operation, offset),
-        }
-    }
-
-    fn disassemble_simple_instruction(&self, operation: Opcode, offset: usize) -> usize {
-        println!("{operation:?}");
-        offset + 1
-    }
-
-    fn disassemble_constant_instruction(&self, operation: Opcode, offset: usize) -> usize {
-        let constant_index = self.bytes[offset + 1] as usize;
-        let constant = &self.constants[constant_index];
-        println!("{: <14} [constant: {constant}]", format!("{operation:?}"));
-        offset + 2
-    }
-
-    fn disassemble_call_instruction(&self, operation: Opcode, offset: usize) -> usize {
-        let arguments = self.bytes[offset + 1] as usize;
-        println!("{: <14} [args: {arguments}]", format!("{operation:?}"));
-        offset + 2
-    }
-
-    fn disassemble_byte_instruction(&self, operation: Opcode, offset: usize) -> usize {
-        let stack_index = self.bytes[offset + 1];
-        println!(
-            "{: <14} [stack_index: {stack_index}]",
-            format!("{operation:?}")
-        );
-        offset + 2
-    }
-
-    fn disassemble_jump_instruction(&self, operation: Opcode, offset: usize) -> usize {
-        let byte_1 = self.bytes[offset + 1];
-        let byte_2 = self.bytes[offset + 2];
-        let jump_offset = u16::from_le_bytes([byte_1, byte_2]);
-        println!("{: <14} [offset: {jump_offset}]", format!("{operation:?}"));
-        offset + 3
-    }
-
-    fn disassemble_invoke_instruction(&self, operation: Opcode, offset: usize) -> usize {
-        let constant_index = self.bytes[offset + 1] as usize;
-        let argument_count = self.bytes[offset + 2];
-        let constant = &self.constants[constant_index];
-        println!(
-            "{: <14} [args: {argument_count}, constant: {constant}]",
-            format!("{operation:?}")
-        );
-        offset + 3
+    /// Render every instruction as a JSON object of its offset, line, opcode
+    /// and named operands.
+    pub fn disassemble_json(&self) -> String {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.bytes.len() {
+            let line = self.lines.get(offset).copied().unwrap_or_default();
+            let Some((operation, operands, next_offset)) = self.decode_instruction(offset) else {
+                instructions.push(format!(
+                    "  {{\"offset\": {offset}, \"line\": {line}, \"opcode\": null, \"operands\": {{}}}}"
+                ));
+                offset += 1;
+                continue;
+            };
+            let operands = operands
+                .iter()
+                .map(|(name, operand)| match operand {
+                    Operand::Number(number) => format!("\"{name}\": {number}"),
+                    Operand::Text(text) => format!("\"{name}\": {}", json_string(text)),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            instructions.push(format!(
+                "  {{\"offset\": {offset}, \"line\": {line}, \"opcode\": \"{operation:?}\", \"operands\": {{{operands}}}}}"
+            ));
+            offset = next_offset;
+        }
+        match instructions.is_empty() {
+            true => "[]".into(),
+            false => format!("[\n{}\n]", instructions.join(",\n")),
+        }
+    }
+
+    pub fn disassemble_instruction(&self, offset: usize) -> (String, usize) {
+        let line = self.lines.get(offset).copied().unwrap_or_default();
+        let prefix = match offset > 0 && self.lines.get(offset - 1) == Some(&line) {
+            true => format!("{offset:0>4} {:>9}  ", "|"),
+            false => format!("{offset:0>4} {line:>9}  "),
+        };
+        let Some((operation, operands, next_offset)) = self.decode_instruction(offset) else {
+            let byte = self.bytes.get(offset);
+            let instruction = match byte.and_then(|&byte| Opcode::from_byte(byte)) {
+                Some(operation) => format!("{operation:?} with invalid operands"),
+                None => format!("Unknown opcode {}", byte.unwrap_or(&0)),
+            };
+            return (format!("{prefix}{instruction}"), offset + 1);
+        };
+        let instruction = match operands.is_empty() {
+            true => format!("{operation:?}"),
+            false => {
+                let operands = operands
+                    .iter()
+                    .map(|(name, operand)| format!("{name}: {operand}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("{: <14} [{operands}]", format!("{operation:?}"))
+            }
+        };
+        (format!("{prefix}{instruction}"), next_offset)
+    }
+
+    /// The instruction at `offset`, or `None` if its opcode is unknown or its
+    /// operands are missing or out of range.
+    fn decode_instruction(&self, offset: usize) -> Option<(Opcode, Operands, usize)> {
+        let operation = Opcode::from_byte(*self.bytes.get(offset)?)?;
+        let (operands, next_offset) = match operation {
+            Opcode::Return
+            | Opcode::Negate
+            | Opcode::Add
+            | Opcode::Subtract
+            | Opcode::Multiply
+            | Opcode::Divide
+            | Opcode::FloorDivide
+            | Opcode::Nil
+            | Opcode::True
+            | Opcode::False
+            | Opcode::Not
+            | Opcode::Equal
+            | Opcode::Greater
+            | Opcode::Less
+            | Opcode::In
+            | Opcode::Print
+            | Opcode::DebugPrint
+            | Opcode::PrettyPrint
+            | Opcode::Pop
+            | Opcode::Index
+            | Opcode::Length
+            | Opcode::Dup
+            | Opcode::Nop => (vec![], offset + 1),
+            Opcode::GetLocal | Opcode::SetLocal => self.decode_local_operands(offset)?,
+            Opcode::Constant => self.decode_constant_operands(offset)?,
+            Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::Jump | Opcode::Loop => {
+                self.decode_jump_operands(offset)?
+            }
+            Opcode::Call => self.decode_byte_operand("args", offset)?,
+            Opcode::List => self.decode_byte_operand("elements", offset)?,
+            Opcode::PopN => self.decode_byte_operand("count", offset)?,
+            Opcode::Native => self.decode_native_operands(offset)?,
+        };
+        Some((operation, operands, next_offset))
+    }
+
+    fn decode_byte_operand(&self, name: &'static str, offset: usize) -> Option<(Operands, usize)> {
+        let byte = *self.bytes.get(offset + 1)? as usize;
+        Some((vec![(name, Operand::Number(byte))], offset + 2))
+    }
+
+    fn decode_constant_operands(&self, offset: usize) -> Option<(Operands, usize)> {
+        let constant_index = *self.bytes.get(offset + 1)? as usize;
+        let constant = self.constants.get(constant_index)?.to_string();
+        Some((vec![("constant", Operand::Text(constant))], offset + 2))
+    }
+
+    fn decode_native_operands(&self, offset: usize) -> Option<(Operands, usize)> {
+        let index = *self.bytes.get(offset + 1)? as usize;
+        let name = NATIVE_FUNCTIONS.get(index)?.0;
+        Some((vec![("native", Operand::Text(name.into()))], offset + 2))
+    }
+
+    fn decode_local_operands(&self, offset: usize) -> Option<(Operands, usize)> {
+        let stack_index = *self.bytes.get(offset + 1)? as usize;
+        let depth = *self.bytes.get(offset + 2)? as usize;
+        let operands = vec![
+            ("stack_index", Operand::Number(stack_index)),
+            ("depth", Operand::Number(depth)),
+        ];
+        Some((operands, offset + 3))
+    }
+
+    fn decode_jump_operands(&self, offset: usize) -> Option<(Operands, usize)> {
+        let byte_1 = *self.bytes.get(offset + 1)?;
+        let byte_2 = *self.bytes.get(offset + 2)?;
+        let jump_offset = u16::from_le_bytes([byte_1, byte_2]) as usize;
+        Some((vec![("offset", Operand::Number(jump_offset))], offset + 3))
     }
 }
@@ -7,10 +7,35 @@ pub const LOCALS_MAX_COUNT: usize = U8_COUNT_USIZE;
 pub const CONSTANTS_MAX_COUNT: usize = U8_COUNT_USIZE;
 pub const STACK_VALUES_MAX_COUNT: usize = CALL_FRAMES_MAX_COUNT * U8_COUNT_USIZE;
 pub const FUNCTION_ARITY_MAX_COUNT: u8 = 10;
+pub const EXPRESSION_DEPTH_MAX_COUNT: usize = 256;
+pub const BLOCK_DEPTH_MAX_COUNT: usize = 256;
 
-pub fn print_function(name: &str) -> String {
+/// Version of the serialized bytecode format. Bump whenever `Opcode`
+/// numbering or the layout written by `Code::serialize` changes, so stale
+/// `.chefc` files are rejected rather than misread.
+pub const BYTECODE_VERSION: u8 = 1;
+
+pub fn print_function(name: &str, arity: u8) -> String {
     match name.is_empty() {
         true => "<script>".into(),
-        false => format!("<fn {}>", name),
+        false => format!("<fn {name}/{arity}>"),
+    }
+}
+
+/// Quote and escape text as a JSON string.
+pub fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for char in text.chars() {
+        match char {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            char if char.is_control() => escaped.push_str(&format!("\\u{:04x}", char as u32)),
+            char => escaped.push(char),
+        }
     }
+    escaped.push('"');
+    escaped
 }
@@ -1,11 +1,25 @@
+use std::collections::HashMap;
+
 use crate::code::Opcode;
-use crate::common::{FUNCTION_ARITY_MAX_COUNT, LOCALS_MAX_COUNT};
-use crate::native_functions::declare_native_functions;
+use crate::common::{
+    BLOCK_DEPTH_MAX_COUNT, EXPRESSION_DEPTH_MAX_COUNT, FUNCTION_ARITY_MAX_COUNT, LOCALS_MAX_COUNT,
+    U8_MAX_USIZE,
+};
+use crate::error::{Diagnostic, Severity};
+use crate::native_functions::NATIVE_FUNCTIONS;
 use crate::rules::{ParseFunctionKind, Precedence};
 use crate::scanner::{Token, TokenKind};
 use crate::value::{Function, Value};
 use crate::{code::Code, scanner::Scanner};
 
+/// How much code had been emitted at some point, so that anything emitted
+/// after it can be discarded again.
+#[derive(Clone, Copy)]
+struct CodeMark {
+    bytes: usize,
+    constants: usize,
+}
+
 #[derive(PartialEq)]
 enum ArgumentPosition {
     First,
@@ -13,31 +27,71 @@ enum ArgumentPosition {
     Last,
 }
 
+/// Settings that relax or tighten how a recipe is compiled.
+#[derive(Debug, Clone, Copy)]
+pub struct CompileOptions {
+    /// Accept steps in any numbering, e.g. `1.` for every step.
+    pub auto_number: bool,
+    /// Warn about expression steps that neither call nor assign anything.
+    pub warn_no_effect: bool,
+    /// Treat a top-level `serve` as tasting its value and stopping, as the
+    /// REPL does, instead of rejecting it.
+    pub repl: bool,
+    /// Columns between tab stops when reporting a diagnostic's column.
+    pub tab_width: usize,
+    /// Refuse `include` lines, which read other files, as `--sandbox` does.
+    pub sandbox: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            auto_number: false,
+            warn_no_effect: false,
+            repl: false,
+            tab_width: 4,
+            sandbox: false,
+        }
+    }
+}
+
 pub struct Compiler<'src> {
     scanner: Scanner<'src>,
     previous: Token<'src>,
     current: Token<'src>,
     context: CompilerContext<'src>,
-    had_error: bool,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
+    expression_depth: usize,
+    section: Option<&'static str>,
+    utensil_arities: HashMap<&'src str, u8>,
+    has_side_effect: bool,
+    options: CompileOptions,
     code: Code,
 }
 
 impl<'src> Compiler<'src> {
     pub fn new(source: &'src str) -> Self {
-        let initial_token = Token::new("", 1, TokenKind::Error);
+        let initial_token = Token::new("", 1, 1, TokenKind::Error);
         let context = CompilerContext::new();
         let mut compiler = Self {
             scanner: Scanner::new(source),
             previous: initial_token,
             current: initial_token,
-            had_error: false,
+            diagnostics: Vec::new(),
             panic_mode: false,
+            expression_depth: 0,
+            section: None,
+            utensil_arities: HashMap::new(),
+            has_side_effect: false,
+            options: CompileOptions::default(),
             code: Code::new(),
             context,
         };
-        for (name, function) in declare_native_functions() {
-            compiler.emit_constant(Value::NativeFunction(function));
+        // Natives are loaded by index so they don't use up the constants table
+        for (index, (name, _)) in NATIVE_FUNCTIONS.into_iter().enumerate() {
+            compiler.emit(Opcode::Native as u8);
+            compiler.emit(index as u8);
             if let Err(err) = compiler.add_local(name) {
                 compiler.error(err);
             }
@@ -45,6 +99,12 @@ impl<'src> Compiler<'src> {
         compiler
     }
 
+    pub fn with_options(mut self, options: CompileOptions) -> Self {
+        self.scanner.set_tab_width(options.tab_width);
+        self.options = options;
+        self
+    }
+
     fn begin_compiler(&mut self) {
         let compiler_context = CompilerContext::new();
         let enclosing_compiler_context = std::mem::replace(&mut self.context, compiler_context);
@@ -52,6 +112,10 @@ impl<'src> Compiler<'src> {
     }
 
     fn end_compiler(&mut self) {
+        debug_assert!(
+            self.context.active_else.is_none(),
+            "A block left its 'check' unpatched."
+        );
         self.emit_return();
         drop(
             self.context
@@ -61,35 +125,69 @@ impl<'src> Compiler<'src> {
         )
     }
 
-    pub fn compile(mut self) -> Option<Code> {
+    pub fn compile(mut self) -> Result<Code, Vec<Diagnostic>> {
         self.advance();
         self.parse_title();
+        self.parse_about();
         self.parse_ingredients();
         self.parse_utensils();
         self.consume(
             TokenKind::StepsHeader,
             "Expect 'Recipe' to contain 'Steps' section",
         );
+        self.section = Some("Steps");
         self.block();
+        debug_assert!(
+            self.context.active_else.is_none(),
+            "A block left its 'check' unpatched."
+        );
         self.emit_return();
         #[cfg(feature = "debug_code")]
         self.debug();
-        match self.had_error {
-            true => None,
-            false => Some(self.code),
+        match self
+            .diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+        {
+            true => Err(self.diagnostics),
+            false => {
+                self.code.warnings = self.diagnostics;
+                Ok(self.code)
+            }
         }
     }
 
     fn parse_title(&mut self) {
+        if self.check(TokenKind::Eof) {
+            self.error_at_current("Recipe is empty, expected it to begin with 'Recipe'.");
+            return;
+        }
         if !self.r#match(TokenKind::Recipe) {
             self.error("Script must begin with 'Recipe'.");
         }
     }
 
+    fn parse_about(&mut self) {
+        if !self.r#match(TokenKind::AboutHeader) {
+            return;
+        }
+        self.section = Some("About");
+        while self.r#match(TokenKind::AboutEntry) {
+            match self.previous.lexeme.split_once(':') {
+                Some((key, value)) if !key.trim().is_empty() => self
+                    .code
+                    .metadata
+                    .push((key.trim().into(), value.trim().into())),
+                _ => self.error("Expect 'key: value' line."),
+            }
+        }
+    }
+
     fn parse_ingredients(&mut self) {
         if !self.r#match(TokenKind::IngredientsHeader) {
             return;
         }
+        self.section = Some("Ingredients");
         while !self.is_end_ingredients() {
             if !self.check(TokenKind::Var) {
                 self.error_at_current("Expect ingredient name.");
@@ -104,6 +202,7 @@ impl<'src> Compiler<'src> {
         if !self.r#match(TokenKind::UtensilsHeader) {
             return;
         }
+        self.section = Some("Utensils");
         while !self.is_end_utensils() {
             if !self.check(TokenKind::FunIdent) {
                 self.error_at_current("Expect utensil name.");
@@ -120,6 +219,10 @@ impl<'src> Compiler<'src> {
             || self.check(TokenKind::Eof)
     }
 
+    fn is_end_ingredient(&self) -> bool {
+        self.is_end_ingredients() || self.check(TokenKind::Var)
+    }
+
     fn is_end_utensils(&self) -> bool {
         self.check(TokenKind::StepsHeader) || self.check(TokenKind::Eof)
     }
@@ -139,8 +242,15 @@ impl<'src> Compiler<'src> {
     fn fun_declaration(&mut self) {
         self.consume(TokenKind::FunIdent, "Expect utensil identifier name.");
         let name = self.previous.lexeme;
+        let is_defined = self.utensil_arities.contains_key(name);
+        if is_defined {
+            self.error(&format!("Utensil '{name}' already defined."));
+        }
         self.function();
-        self.define_variable(name);
+        // Already reported, don't repeat it as a clashing local
+        if !is_defined {
+            self.define_variable(name);
+        }
     }
 
     fn function(&mut self) {
@@ -157,13 +267,28 @@ impl<'src> Compiler<'src> {
                     self.error_at_current("Can't have more than 10 parameters.");
                     return;
                 }
+                if function_arity > 0 && self.check(TokenKind::Step) {
+                    let message = format!(
+                        "Expect parameter {} after '{}'.",
+                        function_arity + 1,
+                        self.previous.lexeme
+                    );
+                    self.error(&message);
+                    break;
+                }
                 function_arity += 1;
-                self.consume(TokenKind::Ident, "Expect parameter name.");
+                if !(self.r#match(TokenKind::Ident) || self.r#match(TokenKind::VarIdent)) {
+                    self.error_at_current("Expect parameter name.");
+                }
+                self.check_native_shadowing(self.previous);
                 self.define_variable(self.previous.lexeme);
                 match self.current.kind {
                     TokenKind::Comma => {
                         if order == ArgumentPosition::Last {
-                            self.error("Invalid ',' after final argument.");
+                            let message = format!(
+                                "Invalid ',' after final parameter (parameter {function_arity})."
+                            );
+                            self.error_at_current(&message);
                         }
                         order = ArgumentPosition::Middle;
                         self.advance();
@@ -171,7 +296,10 @@ impl<'src> Compiler<'src> {
                     }
                     TokenKind::ParameterAnd => {
                         if order == ArgumentPosition::Last {
-                            self.error("Invalid 'and' after final argument.");
+                            let message = format!(
+                                "Invalid 'and' after final parameter (parameter {function_arity})."
+                            );
+                            self.error_at_current(&message);
                         }
                         order = ArgumentPosition::Last;
                         self.advance();
@@ -179,7 +307,10 @@ impl<'src> Compiler<'src> {
                     }
                     TokenKind::Step => {
                         if order == ArgumentPosition::Middle {
-                            self.error("Function parameters should be a list where the final element is preceded by 'and'.");
+                            let message = format!(
+                                "Expect 'and' before final parameter (parameter {function_arity})."
+                            );
+                            self.error(&message);
                         }
                         break;
                     }
@@ -190,6 +321,7 @@ impl<'src> Compiler<'src> {
                 }
             }
         }
+        self.utensil_arities.insert(function_name, function_arity);
         let fun_jump = self.emit_jump(Opcode::Jump as u8);
         let function = Function {
             name: function_name.into(),
@@ -212,15 +344,59 @@ impl<'src> Compiler<'src> {
 
     fn var_declaration(&mut self) {
         self.consume(TokenKind::Var, "Expect 'set' ingredient identifier.");
+        // `set 2 egg` is short for `set egg to 2`, and may name a unit that
+        // is only there to read well, e.g. `set 200 grams flour`
+        let quantity = match self.r#match(TokenKind::Number) {
+            true => {
+                let quantity = self.previous.lexeme.parse().ok();
+                self.r#match(TokenKind::Ident);
+                quantity
+            }
+            false => None,
+        };
         self.consume(TokenKind::VarIdent, "Expect ingredient identifier name.");
-        self.define_variable(self.previous.lexeme);
-        if self.r#match(TokenKind::Equal) {
-            self.expression();
-        } else {
-            self.emit(Opcode::Nil as u8);
+        let name = self.previous.lexeme;
+        self.define_variable(name);
+        if let Some(quantity) = quantity {
+            self.emit_constant(Value::Number(quantity));
+            if !self.is_end_ingredient() {
+                self.error_at_current_found("Unexpected token after ingredient declaration.");
+            }
+            return;
+        }
+        if !self.r#match(TokenKind::Equal) {
+            self.emit_constant(Value::Unset(name.into()));
+            if !self.is_end_ingredient() {
+                self.error_at_current_found(
+                    "Expect 'to' or a new ingredient after ingredient name.",
+                );
+            }
+            return;
+        }
+        if self.is_end_ingredient() {
+            self.error("Expect value after 'to'.");
+            return;
+        }
+        self.expression();
+        if !self.is_end_ingredient() {
+            self.error_at_current_found("Unexpected token after ingredient declaration.");
+        }
+    }
+
+    fn check_native_shadowing(&mut self, token: Token<'src>) {
+        // Natives are script locals, so redeclaring one there is already an error
+        if self.context.enclosing.is_none() {
+            return;
         }
-        if !(self.is_end_ingredients() || self.check(TokenKind::Var)) {
-            self.error_at_current("Expect 'set' ingredient identifier.");
+        let is_native = NATIVE_FUNCTIONS
+            .iter()
+            .any(|(name, _)| *name == token.lexeme);
+        if is_native {
+            let message = format!(
+                "'{}' shadows the native utensil of the same name.",
+                token.lexeme
+            );
+            self.warning_at(token, &message);
         }
     }
 
@@ -249,30 +425,44 @@ impl<'src> Compiler<'src> {
     }
 
     fn statement(&mut self) {
-        if let Some(else_jump) = self.context.active_else {
+        if let Some(pending_else) = self.context.active_else.take() {
             match self.r#match(TokenKind::Else) {
                 true => {
+                    let else_start = self.code_mark();
                     self.else_statement();
-                    self.patch_jump(else_jump);
+                    match pending_else {
+                        PendingElse::Jump(else_jump) => self.patch_jump(else_jump),
+                        PendingElse::Discard => self.discard_code_from(else_start),
+                        PendingElse::Inline => {}
+                    }
                     self.context.active_else = None;
                     return;
                 }
                 false => {
-                    self.patch_jump(else_jump);
-                    self.context.active_else = None;
+                    if let PendingElse::Jump(else_jump) = pending_else {
+                        self.patch_jump(else_jump);
+                    }
                 }
             };
         }
         if self.check(TokenKind::Step) {
             self.error("Empty instruction.");
         } else if self.r#match(TokenKind::Print) {
-            self.print_statement();
+            self.print_statement(Opcode::Print);
+        } else if self.r#match(TokenKind::DebugPrint) {
+            self.print_statement(Opcode::DebugPrint);
+        } else if self.r#match(TokenKind::PrettyPrint) {
+            self.print_statement(Opcode::PrettyPrint);
         } else if self.r#match(TokenKind::If) {
             self.if_statement();
         } else if self.r#match(TokenKind::Return) {
             self.return_statement();
         } else if self.r#match(TokenKind::While) {
             self.while_statement();
+        } else if self.r#match(TokenKind::Each) {
+            self.each_statement();
+        } else if self.r#match(TokenKind::Repeat) {
+            self.repeat_statement();
         } else if self.r#match(TokenKind::Else) {
             self.error("'otherwise' clause without a matching 'check' clause.");
         } else {
@@ -288,12 +478,25 @@ impl<'src> Compiler<'src> {
         self.context.scope_ordering.pop();
     }
 
+    /// Whether steps being compiled belong to the recipe's own Steps, rather
+    /// than a utensil or a nested block. Only these may run to the end of
+    /// the file without an 'end'.
+    fn is_script_level(&self) -> bool {
+        self.context.enclosing.is_none() && self.context.scope_ordering.len() == 1
+    }
+
     fn block(&mut self) {
+        // Nested steps recurse like nested expressions, so bound them too
+        if self.context.scope_ordering.len() > BLOCK_DEPTH_MAX_COUNT {
+            self.error_at_current("Steps too deeply nested.");
+            self.end_scope();
+            return;
+        }
         if !self.r#match(TokenKind::Step) {
             self.end_scope();
             return;
         }
-        if self.previous.lexeme != "1.".to_string() {
+        if !self.options.auto_number && self.previous.lexeme != "1." {
             self.error("Expect instruction to start from '1.'");
             self.advance();
             return;
@@ -301,21 +504,21 @@ impl<'src> Compiler<'src> {
         let mut end_found = false;
         loop {
             let current_step = self.context.scope_ordering.last_mut().unwrap();
-            if self.previous.lexeme != format!("{current_step}.") {
+            if !self.options.auto_number && self.previous.lexeme != format!("{current_step}.") {
                 self.error("Expect instruction numbers to increase.");
                 break;
             }
             match current_step.checked_add(1) {
                 Some(n) => *current_step = n,
                 None => {
-                    self.error("Too many steps.");
+                    self.error(&format!(
+                        "Too many steps, a block can have at most {}.",
+                        u32::MAX
+                    ));
                     break;
                 }
             };
             if self.r#match(TokenKind::RightBrace) {
-                if let Some(else_jump) = self.context.active_else {
-                    self.patch_jump(else_jump);
-                }
                 end_found = true;
                 break;
             }
@@ -327,20 +530,43 @@ impl<'src> Compiler<'src> {
                 break;
             }
         }
-        if !end_found {
+        // A 'check' ending the block can't be followed by an 'otherwise' from
+        // outside it, whether or not the block was closed with 'end'
+        if let Some(PendingElse::Jump(else_jump)) = self.context.active_else.take() {
+            self.patch_jump(else_jump);
+        }
+        let is_implicit_end = self.is_script_level() && self.check(TokenKind::Eof);
+        if !end_found && !is_implicit_end {
             self.error_at_current("Instructions must terminate with 'end'.");
         }
         self.end_scope();
     }
 
-    fn print_statement(&mut self) {
+    fn print_statement(&mut self, operation: Opcode) {
         self.expression();
         self.check_end_step();
-        self.emit(Opcode::Print as u8);
+        self.emit(operation as u8);
     }
 
     fn if_statement(&mut self) {
+        let condition_start = self.code_mark();
         self.expression();
+        if let Some(is_truthy) = self.literal_condition(condition_start.bytes) {
+            // Only the branch a literal condition selects needs any bytecode
+            self.discard_code_from(condition_start);
+            let then_start = self.code_mark();
+            self.begin_scope();
+            self.block();
+            let pending_else = match is_truthy {
+                true => PendingElse::Discard,
+                false => {
+                    self.discard_code_from(then_start);
+                    PendingElse::Inline
+                }
+            };
+            self.context.active_else = Some(pending_else);
+            return;
+        }
         let then_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         self.emit(Opcode::Pop as u8);
         self.begin_scope();
@@ -348,7 +574,30 @@ impl<'src> Compiler<'src> {
         let else_jump = self.emit_jump(Opcode::Jump as u8);
         self.patch_jump(then_jump);
         self.emit(Opcode::Pop as u8);
-        self.context.active_else = Some(else_jump);
+        self.context.active_else = Some(PendingElse::Jump(else_jump));
+    }
+
+    fn literal_condition(&self, condition_start: usize) -> Option<bool> {
+        match self.code.bytes[condition_start..] {
+            [byte] if byte == Opcode::True as u8 => Some(true),
+            [byte] if byte == Opcode::False as u8 || byte == Opcode::Nil as u8 => Some(false),
+            _ => None,
+        }
+    }
+
+    fn code_mark(&self) -> CodeMark {
+        CodeMark {
+            bytes: self.code.bytes.len(),
+            constants: self.code.constants_count,
+        }
+    }
+
+    /// Drop everything emitted since `mark`, including constants only the
+    /// dropped code used, so dead code doesn't count towards the limit.
+    fn discard_code_from(&mut self, mark: CodeMark) {
+        self.code.bytes.truncate(mark.bytes);
+        self.code.lines.truncate(mark.bytes);
+        self.code.truncate_constants(mark.constants);
     }
 
     fn else_statement(&mut self) {
@@ -357,11 +606,16 @@ impl<'src> Compiler<'src> {
     }
 
     fn return_statement(&mut self) {
-        if self.context.scope_ordering.len() == 1 {
+        let top_level = self.context.scope_ordering.len() == 1;
+        if top_level && !self.options.repl {
             self.error("Can't return from top-level code.");
         }
         self.expression();
         self.check_end_step();
+        if top_level && self.options.repl {
+            self.emit(Opcode::Print as u8);
+            self.emit(Opcode::Nil as u8);
+        }
         self.emit(Opcode::Return as u8);
     }
 
@@ -375,7 +629,7 @@ impl<'src> Compiler<'src> {
     fn patch_jump(&mut self, index: usize) {
         let jump_offset = self.code.bytes.len() - index - 2;
         if jump_offset > u16::MAX as usize {
-            self.error("Loop body too large.");
+            self.error("Too much code to jump over.");
             return;
         }
         let bytes = (jump_offset as u16).to_le_bytes();
@@ -384,33 +638,163 @@ impl<'src> Compiler<'src> {
     }
 
     fn while_statement(&mut self) {
-        let loop_start = self.code.bytes.len();
+        let mut loop_start = self.code.bytes.len();
+        self.expression();
+
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+        self.emit(Opcode::Pop as u8);
+        if self.r#match(TokenKind::Then) {
+            // The increment follows the condition in the source but runs
+            // after the body, so jump over it into the body first
+            let body_jump = self.emit_jump(Opcode::Jump as u8);
+            let increment_start = self.code.bytes.len();
+            self.expression();
+            self.emit(Opcode::Pop as u8);
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+        self.begin_scope();
+        self.block();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit(Opcode::Pop as u8);
+    }
+
+    fn each_statement(&mut self) {
+        if !(self.r#match(TokenKind::Ident) || self.r#match(TokenKind::VarIdent)) {
+            self.error_at_current("Expect ingredient name after 'each'.");
+            return;
+        }
+        let element_name = self.previous.lexeme;
+        self.check_native_shadowing(self.previous);
+        self.consume(TokenKind::In, "Expect 'in' after 'each' ingredient name.");
+        let locals_start = self.context.locals_count;
+
+        // Hidden locals hold the list being iterated and the current index
         self.expression();
+        let list_slot = self.add_hidden_local();
+        self.emit_constant(Value::Number(0.0));
+        let index_slot = self.add_hidden_local();
 
+        let loop_start = self.code.bytes.len();
+        self.emit_get_local(index_slot);
+        self.emit_get_local(list_slot);
+        self.emit(Opcode::Length as u8);
+        self.emit(Opcode::Less as u8);
         let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         self.emit(Opcode::Pop as u8);
+
+        self.emit_get_local(list_slot);
+        self.emit_get_local(index_slot);
+        self.emit(Opcode::Index as u8);
+        self.define_variable(element_name);
         self.begin_scope();
         self.block();
+        self.emit(Opcode::Pop as u8);
+        self.truncate_locals(locals_start + 2);
+
+        self.emit_get_local(index_slot);
+        self.emit_constant(Value::Number(1.0));
+        self.emit(Opcode::Add as u8);
+        self.emit(Opcode::SetLocal as u8);
+        self.emit(index_slot);
+        self.emit(0);
+        self.emit(Opcode::Pop as u8);
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
+        // The failed condition, index and list
+        self.emit_pops(3);
+        self.truncate_locals(locals_start);
+    }
+
+    fn repeat_statement(&mut self) {
+        let locals_start = self.context.locals_count;
+
+        // A hidden local counts down the remaining repetitions
+        self.expression();
+        self.consume(TokenKind::Times, "Expect 'times' after repeat count.");
+        let count_slot = self.add_hidden_local();
+
+        let loop_start = self.code.bytes.len();
+        self.emit_get_local(count_slot);
+        self.emit_constant(Value::Number(0.0));
+        self.emit(Opcode::Greater as u8);
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         self.emit(Opcode::Pop as u8);
+
+        self.begin_scope();
+        self.block();
+
+        self.emit_get_local(count_slot);
+        self.emit_constant(Value::Number(1.0));
+        self.emit(Opcode::Subtract as u8);
+        self.emit(Opcode::SetLocal as u8);
+        self.emit(count_slot);
+        self.emit(0);
+        self.emit(Opcode::Pop as u8);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        // The failed condition and count
+        self.emit_pops(2);
+        self.truncate_locals(locals_start);
+    }
+
+    fn add_hidden_local(&mut self) -> u8 {
+        let slot = self.context.locals_count as u8;
+        if let Err(err) = self.add_local(" ") {
+            self.error(err);
+        }
+        slot
+    }
+
+    fn truncate_locals(&mut self, locals_count: usize) {
+        while self.context.locals_count > locals_count {
+            self.context.locals_count -= 1;
+            self.context.locals[self.context.locals_count] = "";
+        }
+    }
+
+    fn emit_pops(&mut self, count: u8) {
+        match count {
+            0 => {}
+            1 => self.emit(Opcode::Pop as u8),
+            _ => {
+                self.emit(Opcode::PopN as u8);
+                self.emit(count);
+            }
+        }
+    }
+
+    fn emit_get_local(&mut self, slot: u8) {
+        self.emit(Opcode::GetLocal as u8);
+        self.emit(slot);
+        self.emit(0);
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
-        self.emit(Opcode::Loop as u8);
-        let offset = self.code.bytes.len() + 2 - loop_start;
+        // The offset is measured from after the three byte `Loop` instruction
+        let offset = self.code.bytes.len() + 3 - loop_start;
         if offset > u16::MAX as usize {
             self.error("Loop body too large.");
             return;
         }
         let bytes = (offset as u16).to_le_bytes();
+        self.emit(Opcode::Loop as u8);
         self.emit(bytes[0]);
         self.emit(bytes[1]);
     }
 
     fn expression_statement(&mut self) {
+        let start = self.current;
+        self.has_side_effect = false;
         self.expression();
+        if self.options.warn_no_effect && !self.has_side_effect {
+            self.warning_at(start, "Step has no effect.");
+        }
         self.check_end_step();
         self.emit(Opcode::Pop as u8);
     }
@@ -431,15 +815,27 @@ impl<'src> Compiler<'src> {
             self.advance();
             return;
         }
-        self.error_at_current(message);
+        self.error_at_current_found(message);
     }
 
     fn check_end_step(&mut self) {
-        if self.current.kind != TokenKind::Step {
-            self.error_at_current("Expect next or final instruction in the sequence.")
+        match self.current.kind {
+            TokenKind::Step => {}
+            TokenKind::Eof if self.is_script_level() => {}
+            TokenKind::Eof => self.error_at_current("Expect 'end' step before end of file."),
+            _ => self.error_at_current_found("Expect next or final instruction in the sequence."),
         }
     }
 
+    /// Report an unexpected token, naming it in the message as well.
+    fn error_at_current_found(&mut self, message: &str) {
+        let found = match self.current.kind {
+            TokenKind::Eof => "end of file".into(),
+            _ => format!("'{}'", self.current.lexeme),
+        };
+        self.error_at_current(&format!("{message} (found {found})"));
+    }
+
     fn emit(&mut self, byte: u8) {
         let line = self.previous.line;
         self.code.write(byte, line);
@@ -465,20 +861,36 @@ impl<'src> Compiler<'src> {
         self.error_at(self.current, message);
     }
 
+    fn warning_at(&mut self, token: Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.report(token, message, Severity::Warning);
+    }
+
     fn error_at(&mut self, token: Token, message: &str) {
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
+        self.report(token, message, Severity::Error);
+    }
 
-        match token.kind {
-            TokenKind::Eof => eprint!(" at end of file"),
-            TokenKind::Error => (),
-            _ => eprint!(" at '{}'", token.lexeme),
-        }
-        eprintln!(": {message}");
-        self.had_error = true;
+    fn report(&mut self, token: Token, message: &str, severity: Severity) {
+        let location = match token.kind {
+            TokenKind::Eof => Some("end of file".into()),
+            TokenKind::Error => None,
+            _ => Some(format!("'{}'", token.lexeme)),
+        };
+        self.diagnostics.push(Diagnostic {
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+            severity,
+            location,
+            file: token.file.map(str::to_owned),
+            section: self.section.map(str::to_owned),
+        });
     }
 
     fn emit_return(&mut self) {
@@ -487,10 +899,21 @@ impl<'src> Compiler<'src> {
     }
 
     fn synchronise(&mut self) {
+        // Nothing left to recover at the end of the file, so stay quiet
+        if self.check(TokenKind::Eof) {
+            return;
+        }
         self.panic_mode = false;
         while self.current.kind != TokenKind::Eof {
             match self.current.kind {
-                TokenKind::If | TokenKind::While | TokenKind::Print | TokenKind::Return => {
+                TokenKind::If
+                | TokenKind::While
+                | TokenKind::Each
+                | TokenKind::Repeat
+                | TokenKind::Print
+                | TokenKind::DebugPrint
+                | TokenKind::PrettyPrint
+                | TokenKind::Return => {
                     self.advance();
                     return;
                 }
@@ -504,6 +927,17 @@ impl<'src> Compiler<'src> {
     }
 
     pub fn parse_precedence(&mut self, precedence: Precedence) {
+        // Each nesting level recurses, so bound the depth rather than overflow the stack
+        if self.expression_depth == EXPRESSION_DEPTH_MAX_COUNT {
+            self.error_at_current("Expression too deeply nested.");
+            return;
+        }
+        self.expression_depth += 1;
+        self.parse_rules(precedence);
+        self.expression_depth -= 1;
+    }
+
+    fn parse_rules(&mut self, precedence: Precedence) {
         let can_assign = match self.current.kind == TokenKind::Var {
             true => {
                 self.advance();
@@ -525,13 +959,15 @@ impl<'src> Compiler<'src> {
             }
             return;
         };
+        let operand_start = self.code.bytes.len();
         self.advance();
-        self.execute_rule(prefix_rule, can_assign);
+        self.execute_rule(prefix_rule, can_assign, operand_start, self.previous);
         while precedence <= Precedence::get_rule(self.current.kind).precedence {
             let can_assign = self.previous.kind == TokenKind::Var && Self::can_assign(precedence);
+            let left_end = self.previous;
             self.advance();
             let infix_rule = Precedence::get_rule(self.previous.kind).infix;
-            self.execute_rule(infix_rule, can_assign);
+            self.execute_rule(infix_rule, can_assign, operand_start, left_end);
         }
 
         if Self::can_assign(precedence) && self.r#match(TokenKind::Equal) {
@@ -543,19 +979,30 @@ impl<'src> Compiler<'src> {
         precedence <= Precedence::Assignment
     }
 
-    fn execute_rule(&mut self, kind: ParseFunctionKind, can_assign: bool) {
+    /// Run a parse rule. `operand_start` is where the expression being parsed
+    /// begins, which for an infix rule is the start of its left operand, and
+    /// `left_end` is the last token of that operand.
+    fn execute_rule(
+        &mut self,
+        kind: ParseFunctionKind,
+        can_assign: bool,
+        operand_start: usize,
+        left_end: Token<'src>,
+    ) {
         match kind {
             ParseFunctionKind::None => {}
             ParseFunctionKind::Grouping => Self::grouping(self),
             ParseFunctionKind::Unary => Self::unary(self),
-            ParseFunctionKind::Binary => Self::binary(self),
+            ParseFunctionKind::Binary => Self::binary(self, operand_start),
             ParseFunctionKind::Number => Self::number(self),
             ParseFunctionKind::Literal => Self::literal(self),
             ParseFunctionKind::String => Self::string(self),
             ParseFunctionKind::Variable => Self::variable(self, can_assign),
             ParseFunctionKind::And => Self::and(self),
             ParseFunctionKind::Or => Self::or(self),
-            ParseFunctionKind::Call => Self::call(self),
+            ParseFunctionKind::Call => Self::call(self, left_end),
+            ParseFunctionKind::List => Self::list(self),
+            ParseFunctionKind::Index => Self::index(self),
         }
     }
 
@@ -584,18 +1031,24 @@ impl<'src> Compiler<'src> {
         }
     }
 
-    fn binary(&mut self) {
-        let operator_kind = self.previous.kind;
+    fn binary(&mut self, left_start: usize) {
+        let operator = self.previous;
+        let operator_kind = operator.kind;
+        let right_start = self.code.bytes.len();
         let parse_rule = Precedence::get_rule(operator_kind);
         self.parse_precedence(parse_rule.precedence.next());
+        self.check_literal_comparison(operator, left_start, right_start);
+        self.reuse_left_operand(left_start, right_start);
         match operator_kind {
             TokenKind::Plus => self.emit(Opcode::Add as u8),
             TokenKind::Minus => self.emit(Opcode::Subtract as u8),
             TokenKind::Star => self.emit(Opcode::Multiply as u8),
             TokenKind::Slash => self.emit(Opcode::Divide as u8),
+            TokenKind::FloorSlash => self.emit(Opcode::FloorDivide as u8),
             TokenKind::EqualEqual => self.emit(Opcode::Equal as u8),
             TokenKind::Greater => self.emit(Opcode::Greater as u8),
             TokenKind::Less => self.emit(Opcode::Less as u8),
+            TokenKind::In => self.emit(Opcode::In as u8),
             TokenKind::BangEqual => {
                 self.emit(Opcode::Equal as u8);
                 self.emit(Opcode::Not as u8);
@@ -604,6 +1057,60 @@ impl<'src> Compiler<'src> {
         }
     }
 
+    /// Replace a right operand that reads the same local as the left one,
+    /// e.g. `egg multiply egg`, with a copy of the value already on the stack.
+    fn reuse_left_operand(&mut self, left_start: usize, right_start: usize) {
+        let left = &self.code.bytes[left_start..right_start];
+        let right = &self.code.bytes[right_start..];
+        // A lone get can't contain a jump, so nothing lands between the two
+        if left.first() == Some(&(Opcode::GetLocal as u8)) && left.len() == 3 && left == right {
+            self.code.bytes.truncate(right_start);
+            self.code.lines.truncate(right_start);
+            self.emit(Opcode::Dup as u8);
+        }
+    }
+
+    fn check_literal_comparison(
+        &mut self,
+        operator: Token<'src>,
+        left_start: usize,
+        right_start: usize,
+    ) {
+        let left = self.literal_type(&self.code.bytes[left_start..right_start]);
+        let right = self.literal_type(&self.code.bytes[right_start..]);
+        let (Some(left), Some(right)) = (left, right) else {
+            return;
+        };
+        if left == right {
+            return;
+        }
+        let message = match operator.kind {
+            TokenKind::EqualEqual => format!("Comparing a {left} to a {right} is always false."),
+            TokenKind::BangEqual => format!("Comparing a {left} to a {right} is always true."),
+            TokenKind::Greater | TokenKind::Less => {
+                format!("Ordering a {left} against a {right} fails at runtime.")
+            }
+            _ => return,
+        };
+        self.warning_at(operator, &message);
+    }
+
+    /// The type of an operand compiled from a single literal.
+    fn literal_type(&self, operand: &[u8]) -> Option<&'static str> {
+        match *operand {
+            [byte] if byte == Opcode::Nil as u8 => Some("nil"),
+            [byte] if byte == Opcode::True as u8 || byte == Opcode::False as u8 => Some("boolean"),
+            [byte, index] if byte == Opcode::Constant as u8 => {
+                match self.code.constants[index as usize] {
+                    Value::Number(_) => Some("number"),
+                    Value::String(_) => Some("string"),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
     fn number(&mut self) {
         let Ok(constant) = self.previous.lexeme.parse() else {
             self.error("Could not cast lexeme to number");
@@ -624,11 +1131,46 @@ impl<'src> Compiler<'src> {
     fn string(&mut self) {
         let lexeme_len = self.previous.lexeme.len();
         let lexeme = &self.previous.lexeme[1..{ lexeme_len - 1 }];
-        self.emit_constant(Value::String(lexeme.into()));
+        // Multi-line strings read from Windows files should not keep carriage returns
+        let text = lexeme.replace("\r\n", "\n");
+        match decode_unicode_escapes(&text) {
+            Ok(text) => self.emit_constant(Value::String(text.into())),
+            Err(message) => self.error(&message),
+        }
+    }
+
+    fn list(&mut self) {
+        let mut element_count: usize = 0;
+        if !self.check(TokenKind::RightBracket) {
+            loop {
+                self.expression();
+                element_count += 1;
+                if !self.r#match(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightBracket, "Expect ']' after list elements.");
+        if element_count > U8_MAX_USIZE {
+            self.error("Can't have more than 255 elements in a list.");
+            return;
+        }
+        self.emit(Opcode::List as u8);
+        self.emit(element_count as u8);
+    }
+
+    fn index(&mut self) {
+        self.expression();
+        self.consume(TokenKind::RightBracket, "Expect ']' after index.");
+        self.emit(Opcode::Index as u8);
     }
 
     pub fn variable(&mut self, can_assign: bool) {
-        self.named_variable(self.previous.lexeme, can_assign);
+        let name = self.previous.lexeme;
+        if can_assign && self.previous.kind == TokenKind::FunIdent && self.check(TokenKind::Equal) {
+            self.error(&format!("Cannot assign to utensil '{name}'."));
+        }
+        self.named_variable(name, can_assign);
     }
 
     pub fn named_variable(&mut self, token_name: &str, can_assign: bool) {
@@ -646,6 +1188,7 @@ impl<'src> Compiler<'src> {
 
         if can_assign && self.r#match(TokenKind::Equal) {
             self.expression();
+            self.has_side_effect = true;
             self.emit(set_operation_bytes.0);
             self.emit(set_operation_bytes.1);
             self.emit(set_operation_bytes.2);
@@ -664,19 +1207,36 @@ impl<'src> Compiler<'src> {
     }
 
     fn or(&mut self) {
-        let else_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
-        let end_jump = self.emit_jump(Opcode::Jump as u8);
-        self.patch_jump(else_jump);
+        let end_jump = self.emit_jump(Opcode::JumpIfTrue as u8);
         self.emit(Opcode::Pop as u8);
         self.parse_precedence(Precedence::Or);
         self.patch_jump(end_jump);
     }
 
-    fn call(&mut self) {
+    /// Compile a call of the operand ending at `callee`, which is checked
+    /// against the utensil's arity when it is called by name with 'now'.
+    fn call(&mut self, callee: Token<'src>) {
+        self.has_side_effect = true;
         if self.previous.kind == TokenKind::BareFunctionInvocation {
-            self.emit(Opcode::Call as u8);
-            self.emit(0);
-            return;
+            let arity = match callee.kind {
+                TokenKind::FunIdent => self.utensil_arities.get(callee.lexeme).copied(),
+                _ => None,
+            };
+            if !self.r#match(TokenKind::With) {
+                if let Some(arity @ 1..) = arity {
+                    let name = callee.lexeme;
+                    let noun = match arity {
+                        1 => "argument",
+                        _ => "arguments",
+                    };
+                    let message =
+                        format!("Can't call '{name}' with 'now', it expects {arity} {noun}.");
+                    self.error(&message);
+                }
+                self.emit(Opcode::Call as u8);
+                self.emit(0);
+                return;
+            }
         }
         let Some(argument_count) = self.argument_list() else {
             self.error("Can't have more than 10 arguments.");
@@ -690,6 +1250,15 @@ impl<'src> Compiler<'src> {
         let mut argument_count: u8 = 0;
         let mut order = ArgumentPosition::First;
         loop {
+            if argument_count > 0 && self.check(TokenKind::Step) {
+                let message = format!(
+                    "Expect argument {} after '{}'.",
+                    argument_count + 1,
+                    self.previous.lexeme
+                );
+                self.error(&message);
+                break;
+            }
             self.expression();
             if argument_count == FUNCTION_ARITY_MAX_COUNT {
                 return None;
@@ -698,7 +1267,10 @@ impl<'src> Compiler<'src> {
             match self.current.kind {
                 TokenKind::Comma => {
                     if order == ArgumentPosition::Last {
-                        self.error_at_current("Invalid ',' after final argument.");
+                        let message = format!(
+                            "Invalid ',' after final argument (argument {argument_count})."
+                        );
+                        self.error_at_current(&message);
                     }
                     order = ArgumentPosition::Middle;
                     self.advance();
@@ -706,7 +1278,10 @@ impl<'src> Compiler<'src> {
                 }
                 TokenKind::ParameterAnd => {
                     if order == ArgumentPosition::Last {
-                        self.error_at_current("Invalid 'and' after final argument.");
+                        let message = format!(
+                            "Invalid 'and' after final argument (argument {argument_count})."
+                        );
+                        self.error_at_current(&message);
                     }
                     order = ArgumentPosition::Last;
                     self.advance();
@@ -714,7 +1289,10 @@ impl<'src> Compiler<'src> {
                 }
                 TokenKind::Step => {
                     if order == ArgumentPosition::Middle {
-                        self.error_at_current("Function parameters should be a list where the final element is preceded by 'and'.");
+                        let message = format!(
+                            "Function parameters should be a list where the final element is preceded by 'and' (argument {argument_count})."
+                        );
+                        self.error_at_current(&message);
                     }
                     break;
                 }
@@ -735,10 +1313,21 @@ impl<'src> Compiler<'src> {
 
 struct CompilerContext<'src> {
     enclosing: Option<Box<CompilerContext<'src>>>,
-    scope_ordering: Vec<u16>,
+    scope_ordering: Vec<u32>,
     locals: [&'src str; LOCALS_MAX_COUNT],
     locals_count: usize,
-    active_else: Option<usize>,
+    active_else: Option<PendingElse>,
+}
+
+/// How to compile an `otherwise` following a `check`.
+#[derive(Clone, Copy)]
+enum PendingElse {
+    /// Patch the jump over the else branch once it is compiled.
+    Jump(usize),
+    /// The condition was a truthy literal, so drop the else branch.
+    Discard,
+    /// The condition was a falsey literal, so run the else branch directly.
+    Inline,
 }
 
 impl<'src> CompilerContext<'src> {
@@ -753,7 +1342,7 @@ impl<'src> CompilerContext<'src> {
     }
 
     fn resolve_local(&mut self, token_name: &str, depth: u8) -> Result<(u8, u8), &'static str> {
-        for (index, local_name) in self.locals.iter().enumerate().rev() {
+        for (index, local_name) in self.locals[..self.locals_count].iter().enumerate().rev() {
             if token_name == *local_name {
                 return Ok((index as u8, depth));
             }
@@ -764,3 +1353,33 @@ impl<'src> CompilerContext<'src> {
         Err("Undefined variable.")
     }
 }
+
+/// Replace each `\u{...}` in a string literal with the character whose
+/// code point is given in hex, e.g. `\u{1F370}`.
+fn decode_unicode_escapes(text: &str) -> Result<String, String> {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find("\\u") {
+        decoded.push_str(&rest[..index]);
+        rest = &rest[index + 2..];
+        let Some((digits, after)) = rest.strip_prefix('{').and_then(|rest| rest.split_once('}'))
+        else {
+            return Err("Expect '{' and '}' around the code point of '\\u'.".into());
+        };
+        let is_hex =
+            (1..=6).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_hexdigit());
+        let character = match is_hex {
+            true => u32::from_str_radix(digits, 16)
+                .ok()
+                .and_then(char::from_u32),
+            false => None,
+        };
+        let Some(character) = character else {
+            return Err(format!("Invalid unicode code point '{digits}'."));
+        };
+        decoded.push(character);
+        rest = after;
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
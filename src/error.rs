@@ -1,5 +1,10 @@
+use std::fmt::Display;
+
 use thiserror::Error;
 
+use crate::ansi::Style;
+use crate::common::json_string;
+
 pub type InterpretResult<T> = std::result::Result<T, ChefError>;
 
 #[derive(Debug, Error)]
@@ -10,6 +15,10 @@ pub enum ChefError {
     OutOfBounds,
     #[error("Stack overflow.")]
     StackOverflow,
+    #[error("Stack underflow.")]
+    StackUnderflow,
+    #[error("Undefined variable '{0}'.")]
+    UndefinedVariable(String),
     #[error("Can only call functions.")]
     InvalidCallee,
     #[error("Expected {0} arguments but got {1}.")]
@@ -18,6 +27,345 @@ pub enum ChefError {
     ValueNegationOperation,
     #[error("Operands must be numbers.")]
     ValueNumberOnlyOperation,
+    #[error("Operands must be numbers, got {0} and {1}.")]
+    ValueOrderOperation(&'static str, &'static str),
     #[error("Operands must be two numbers or two strings.")]
     ValueAddOperation,
+    #[error("Operand must be a list.")]
+    ValueListOperation,
+    #[error("List index must be a whole number.")]
+    ListIndex,
+    #[error("Expected a number.")]
+    ExpectedNumber,
+    #[error("Expected a string.")]
+    ExpectedString,
+    #[error("Can only use nil, booleans, numbers, strings and lists of them as keys.")]
+    UnhashableKey,
+    #[error("Bytecode version {0} is not supported, expected version {1}.")]
+    BytecodeVersion(u8, u8),
+    #[error("Invalid bytecode.")]
+    InvalidBytecode,
+    #[error("Output limit of {0} lines exceeded.")]
+    OutputLimitExceeded(usize),
+    #[error("Can only look for a string in a string, or anything in a list, got {0} in {1}.")]
+    ValueInOperation(&'static str, &'static str),
+    /// A native's capability, e.g. "Reading the clock", refused for the
+    /// given reason, e.g. "in the sandbox".
+    #[error("{0} is not allowed {1}.")]
+    CapabilityRefused(&'static str, &'static str),
+}
+
+/// Why [`crate::try_run`] stopped: the recipe didn't compile, or it failed
+/// while running.
+#[derive(Debug)]
+pub enum RunError {
+    Compile(Vec<Diagnostic>),
+    Runtime(ChefError),
+}
+
+impl From<ChefError> for RunError {
+    fn from(error: ChefError) -> Self {
+        Self::Runtime(error)
+    }
+}
+
+impl ChefError {
+    /// A short code that stays the same across releases, looked up with
+    /// `chef --explain`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Compile => "E0001",
+            Self::OutOfBounds => "E0002",
+            Self::StackOverflow => "E0003",
+            Self::StackUnderflow => "E0004",
+            Self::UndefinedVariable(_) => "E0005",
+            Self::InvalidCallee => "E0006",
+            Self::FunctionArity(..) => "E0007",
+            Self::ValueNegationOperation => "E0008",
+            Self::ValueNumberOnlyOperation => "E0009",
+            Self::ValueOrderOperation(..) => "E0010",
+            Self::ValueAddOperation => "E0011",
+            Self::ValueListOperation => "E0012",
+            Self::ListIndex => "E0013",
+            Self::ExpectedNumber => "E0014",
+            Self::ExpectedString => "E0015",
+            Self::UnhashableKey => "E0016",
+            Self::BytecodeVersion(..) => "E0017",
+            Self::InvalidBytecode => "E0018",
+            Self::OutputLimitExceeded(_) => "E0019",
+            Self::ValueInOperation(..) => "E0020",
+            Self::CapabilityRefused(..) => "E0021",
+        }
+    }
+}
+
+const EXPLANATIONS: &[(&str, &str)] = &[
+    (
+        "E0001",
+        "The recipe has errors, reported above this one, so it was not run.
+
+Fix each reported error, starting with the first, as later ones are often
+caused by it.",
+    ),
+    (
+        "E0002",
+        "Something was read from past the end of what holds it. Usually a list
+was indexed past its end, or 'first' or 'last' was given an empty list.
+
+    set egg to [1, 2]
+    taste egg[2]
+
+Indexes start at 0, so the last element of a list of length n is at n minus 1.
+Check a list's length before reading from its ends.
+
+Corrupt bytecode that jumps outside its code or reads a constant or native
+that doesn't exist reports this too. Recompile the recipe from its source.",
+    ),
+    (
+        "E0003",
+        "The VM ran out of stack or call frames, usually from a utensil that
+calls itself without ever stopping.
+
+Check that every recursive call has a case that serves without calling again,
+or raise the limits with --stack-size and --max-frames.",
+    ),
+    (
+        "E0004",
+        "The VM popped a value from an empty stack. This points to a bug in the
+compiler or to hand-edited bytecode, not to the recipe.",
+    ),
+    (
+        "E0005",
+        "An ingredient was read before it was given a value.
+
+    set egg
+    taste egg
+
+Give the ingredient a value with 'set egg to ...' before reading it.",
+    ),
+    (
+        "E0006",
+        "Only utensils can be called.
+
+    set egg to 1
+    egg now
+
+Check that the name being called holds a utensil.",
+    ),
+    (
+        "E0007",
+        "A utensil was called with the wrong number of arguments, e.g. a utensil
+declared 'with x and y' called as 'bake with 1'.
+
+Pass one argument for each of the utensil's parameters.",
+    ),
+    (
+        "E0008",
+        "Only numbers can be negated.
+
+    taste minus \"egg\"
+
+Negate a number instead, or convert the value first.",
+    ),
+    (
+        "E0009",
+        "An arithmetic operation was given something other than numbers.
+
+    taste true minus 1
+
+Operators other than 'add' only work on numbers.",
+    ),
+    (
+        "E0010",
+        "Values were compared with 'above' or 'below' but were not both numbers.
+
+    taste \"egg\" above 1
+
+Only numbers can be ordered. Other values can still be compared with 'is'
+and 'isnt'.",
+    ),
+    (
+        "E0011",
+        "'add' was given a mix of types.
+
+    taste \"egg\" add 1
+
+Add two numbers, or join two strings.",
+    ),
+    (
+        "E0012",
+        "A list operation was used on something that is not a list.
+
+    set egg to 1
+    taste egg[0]
+
+Check that the ingredient holds a list.",
+    ),
+    (
+        "E0013",
+        "A list was indexed with a number that is not whole.
+
+    taste [1, 2][0.5]
+
+Use a whole number, or round the index first.",
+    ),
+    (
+        "E0014",
+        "A native utensil expected a number argument.
+
+Check the arguments passed to the native.",
+    ),
+    (
+        "E0015",
+        "A native utensil expected a string argument.
+
+Check the arguments passed to the native.",
+    ),
+    (
+        "E0016",
+        "A value that can't be compared by contents was used as a key.
+
+Only nil, booleans, numbers, strings and lists of them can be used as keys.",
+    ),
+    (
+        "E0017",
+        "The compiled bytecode was written by a different version of chef.
+
+Recompile the recipe with this version.",
+    ),
+    (
+        "E0018",
+        "The compiled bytecode is truncated or corrupt.
+
+Recompile the recipe from its source.",
+    ),
+    (
+        "E0019",
+        "The recipe tasted more lines than --max-output-lines allows, often
+because a loop never ends.
+
+Check the loop's condition, or raise the limit.",
+    ),
+    (
+        "E0020",
+        "'in' was used with values it can't search.
+
+    taste 1 in \"egg\"
+
+'in' looks for a string inside a string, or for any value among a list's
+elements.",
+    ),
+    (
+        "E0021",
+        "A utensil that reaches outside the recipe was called without being
+allowed to.
+
+The 'env' utensil reads environment variables, which is off by default so a
+recipe can't see the host's settings. Run the recipe with --allow-env if it
+should read them.
+
+Running with --sandbox refuses 'time', 'clock_millis', 'env' and
+'read_line', so an untrusted recipe can't touch the host. The sandbox also
+refuses 'include' lines, which fail to compile rather than read another
+file. Run the recipe without --sandbox if it should use them.",
+    ),
+];
+
+/// The longer explanation of an error code from [`ChefError::code`].
+pub fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(code))
+        .map(|(_, explanation)| *explanation)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// A compile-time message tied to a position in the recipe source.
+///
+/// `location` describes the offending token, e.g. `'egg'` or `end of file`,
+/// and is absent for errors raised by the scanner itself. `section` names
+/// the recipe section being compiled, absent before the first one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub severity: Severity,
+    pub location: Option<String>,
+    pub file: Option<String>,
+    pub section: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn render(&self, style: Style) -> String {
+        let line = match &self.file {
+            Some(file) => style.dim(&format!("[{file}, line {}]", self.line)),
+            None => style.dim(&format!("[line {}]", self.line)),
+        };
+        let severity = match self.severity {
+            Severity::Error => style.error(&self.severity.to_string()),
+            Severity::Warning => style.warning(&self.severity.to_string()),
+        };
+        let section = match &self.section {
+            Some(section) => format!(" in {section}"),
+            None => String::new(),
+        };
+        let location = match &self.location {
+            Some(location) => format!(" at {location}"),
+            None => String::new(),
+        };
+        format!("{line} {severity}{section}{location}: {}", self.message)
+    }
+
+    /// One JSON object with every field, absent ones as `null`.
+    pub fn to_json(&self) -> String {
+        let optional = |field: &Option<String>| match field {
+            Some(text) => json_string(text),
+            None => "null".into(),
+        };
+        format!(
+            "{{\"line\": {}, \"column\": {}, \"severity\": \"{}\", \"message\": {}, \"location\": {}, \"file\": {}, \"section\": {}}}",
+            self.line,
+            self.column,
+            self.severity.to_string().to_lowercase(),
+            json_string(&self.message),
+            optional(&self.location),
+            optional(&self.file),
+            optional(&self.section),
+        )
+    }
+}
+
+/// A JSON array of diagnostics, one per line, for editors to consume.
+pub fn diagnostics_json(diagnostics: &[Diagnostic]) -> String {
+    match diagnostics.is_empty() {
+        true => "[]".into(),
+        false => {
+            let objects: Vec<_> = diagnostics
+                .iter()
+                .map(|diagnostic| format!("  {}", diagnostic.to_json()))
+                .collect();
+            format!("[\n{}\n]", objects.join(",\n"))
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.render(Style::default()))
+    }
 }
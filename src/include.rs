@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Diagnostic, Severity};
+
+const RECIPE_TITLE: &str = "Recipe";
+const ABOUT_HEADER: &str = "About";
+const INGREDIENTS_HEADER: &str = "Ingredients";
+const UTENSILS_HEADER: &str = "Utensils";
+const STEPS_HEADER: &str = "Steps";
+
+/// Ingredient and utensil declarations pulled in from included recipes,
+/// each prefixed with a `//line` directive pointing back at its source.
+#[derive(Default)]
+struct Fragment {
+    ingredients: String,
+    utensils: String,
+}
+
+impl Fragment {
+    fn extend(&mut self, other: Fragment) {
+        self.ingredients.push_str(&other.ingredients);
+        self.utensils.push_str(&other.utensils);
+    }
+}
+
+/// Splice the sections of every `include "file"` line in the recipe's
+/// preamble into the matching sections of `source`.
+///
+/// Included paths are relative to the including file. Line directives keep
+/// diagnostics pointing at the original files. In the `sandbox` any include
+/// is an error, as it would read another file.
+pub fn expand_includes(
+    source: &str,
+    path: &Path,
+    sandbox: bool,
+) -> Result<String, Vec<Diagnostic>> {
+    let mut stack = vec![canonical(path)];
+    // Files already spliced in, so one reached by two includes isn't added twice
+    let mut included = HashSet::new();
+    let mut fragment = Fragment::default();
+    let mut lines = Vec::new();
+    let mut in_preamble = true;
+    for (index, line) in source.split('\n').enumerate() {
+        let trimmed = line.trim();
+        if is_header(header(line)) {
+            in_preamble = false;
+        }
+        match include_name(trimmed) {
+            Some(_) if in_preamble && sandbox => {
+                let message = "Can't include recipes in the sandbox.".into();
+                return Err(vec![include_error((index + 1, None), message)]);
+            }
+            Some(name) if in_preamble => {
+                let resolved = resolve(path, name);
+                let location = (index + 1, None);
+                let read = read_fragment(&resolved, location, &mut stack, &mut included)?;
+                fragment.extend(read);
+                // Keep the line so later line numbers are unchanged
+                lines.push(String::new());
+            }
+            _ => lines.push(line.to_owned()),
+        }
+    }
+    Ok(splice(lines, fragment))
+}
+
+fn splice(lines: Vec<String>, fragment: Fragment) -> String {
+    let mut ingredients_done = fragment.ingredients.is_empty();
+    let mut utensils_done = fragment.utensils.is_empty();
+    let mut output = String::new();
+    for (index, line) in lines.iter().enumerate() {
+        let line_number = index + 1;
+        let trimmed = header(line);
+        if (trimmed == UTENSILS_HEADER || trimmed == STEPS_HEADER) && !ingredients_done {
+            output.push_str(&format!("{INGREDIENTS_HEADER}\n{}", fragment.ingredients));
+            output.push_str(&format!("//line {line_number}\n"));
+            ingredients_done = true;
+        }
+        if trimmed == STEPS_HEADER && !utensils_done {
+            output.push_str(&format!("{UTENSILS_HEADER}\n{}", fragment.utensils));
+            output.push_str(&format!("//line {line_number}\n"));
+            utensils_done = true;
+        }
+        output.push_str(line);
+        if index + 1 < lines.len() {
+            output.push('\n');
+        }
+        let section = match trimmed {
+            INGREDIENTS_HEADER if !ingredients_done => &fragment.ingredients,
+            UTENSILS_HEADER if !utensils_done => &fragment.utensils,
+            _ => continue,
+        };
+        output.push_str(section);
+        output.push_str(&format!("//line {}\n", line_number + 1));
+        match trimmed {
+            INGREDIENTS_HEADER => ingredients_done = true,
+            _ => utensils_done = true,
+        }
+    }
+    output
+}
+
+fn read_fragment(
+    path: &Path,
+    location: (usize, Option<String>),
+    stack: &mut Vec<PathBuf>,
+    included: &mut HashSet<PathBuf>,
+) -> Result<Fragment, Vec<Diagnostic>> {
+    let display = path.display().to_string();
+    let Ok(source) = fs::read_to_string(path) else {
+        let message = format!("Could not read included recipe '{display}'.");
+        return Err(vec![include_error(location, message)]);
+    };
+    let canonical_path = canonical(path);
+    if stack.contains(&canonical_path) {
+        let message = format!("Include cycle through '{display}'.");
+        return Err(vec![include_error(location, message)]);
+    }
+    if !included.insert(canonical_path.clone()) {
+        return Ok(Fragment::default());
+    }
+    stack.push(canonical_path);
+
+    let mut fragment = Fragment::default();
+    let mut section = None;
+    let mut own = Fragment::default();
+    for (index, line) in source.split('\n').enumerate() {
+        let line_number = index + 1;
+        let trimmed = line.trim();
+        let directive = format!("//line {} \"{display}\"\n", line_number + 1);
+        match header(line) {
+            INGREDIENTS_HEADER => {
+                own.ingredients.push_str(&directive);
+                section = Some(INGREDIENTS_HEADER);
+                continue;
+            }
+            UTENSILS_HEADER => {
+                own.utensils.push_str(&directive);
+                section = Some(UTENSILS_HEADER);
+                continue;
+            }
+            STEPS_HEADER => {
+                let message = format!("Included recipe '{display}' can't contain 'Steps'.");
+                return Err(vec![include_error((line_number, Some(display)), message)]);
+            }
+            ABOUT_HEADER => {
+                section = Some(ABOUT_HEADER);
+                continue;
+            }
+            _ => {}
+        }
+        let buffer = match section {
+            Some(INGREDIENTS_HEADER) => &mut own.ingredients,
+            Some(UTENSILS_HEADER) => &mut own.utensils,
+            // Metadata describes the included file, not the recipe using it
+            Some(_) => continue,
+            None => {
+                if let Some(name) = include_name(trimmed) {
+                    let resolved = resolve(path, name);
+                    let location = (line_number, Some(display.clone()));
+                    fragment.extend(read_fragment(&resolved, location, stack, included)?);
+                } else if !matches!(header(line), "" | RECIPE_TITLE) {
+                    let message = format!(
+                        "Included recipe '{display}' can only contain 'Ingredients' and 'Utensils'."
+                    );
+                    return Err(vec![include_error((line_number, Some(display)), message)]);
+                }
+                continue;
+            }
+        };
+        buffer.push_str(line);
+        buffer.push('\n');
+    }
+    stack.pop();
+    // Nested includes come first so this recipe's declarations can use them
+    fragment.extend(own);
+    Ok(fragment)
+}
+
+fn include_error((line, file): (usize, Option<String>), message: String) -> Diagnostic {
+    Diagnostic {
+        line,
+        column: 1,
+        message,
+        severity: Severity::Error,
+        location: Some("'include'".into()),
+        file,
+        section: None,
+    }
+}
+
+fn include_name(line: &str) -> Option<&str> {
+    line.strip_prefix("include ")?
+        .trim()
+        .strip_prefix('"')?
+        .strip_suffix('"')
+}
+
+/// The line without surrounding whitespace or a trailing comment, which the
+/// scanner skips, so `Ingredients // shared` still starts a section.
+fn header(line: &str) -> &str {
+    let line = line.split("//").next().unwrap_or(line);
+    line.split('#').next().unwrap_or(line).trim()
+}
+
+fn is_header(line: &str) -> bool {
+    matches!(line, INGREDIENTS_HEADER | UTENSILS_HEADER | STEPS_HEADER)
+}
+
+fn resolve(including: &Path, name: &str) -> PathBuf {
+    match including.parent() {
+        Some(directory) => directory.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use compiler::Compiler;
+use scanner::{Scanner, TokenKind};
+
+mod ansi;
+mod ast;
+mod code;
+mod common;
+mod compiler;
+mod error;
+mod include;
+mod native_functions;
+mod peephole;
+mod rules;
+mod scanner;
+mod value;
+mod vm;
+
+pub use ansi::Style;
+pub use ast::dump_ast;
+pub use code::{Code, Opcode};
+pub use common::BYTECODE_VERSION;
+pub use compiler::CompileOptions;
+pub use error::{
+    diagnostics_json, explain, ChefError, Diagnostic, InterpretResult, RunError, Severity,
+};
+pub use peephole::optimize;
+pub use value::Value;
+pub use vm::{CallFrame, Frame, State};
+
+/// Compile recipe source into bytecode, returning every diagnostic on failure.
+pub fn compile(source: &str) -> Result<Code, Vec<Diagnostic>> {
+    compile_with_options(source, CompileOptions::default())
+}
+
+/// Compile recipe source with non-default [`CompileOptions`].
+pub fn compile_with_options(
+    source: &str,
+    options: CompileOptions,
+) -> Result<Code, Vec<Diagnostic>> {
+    let mut source = source.to_owned();
+    source.push('\0');
+    Compiler::new(&source).with_options(options).compile()
+}
+
+/// Compile a recipe read from `path`, first splicing in any recipes it
+/// names with `include "file"` lines.
+pub fn compile_with_includes(
+    source: &str,
+    path: &Path,
+    options: CompileOptions,
+) -> Result<Code, Vec<Diagnostic>> {
+    let source = include::expand_includes(source, path, options.sandbox)?;
+    compile_with_options(&source, options)
+}
+
+/// Every reserved word, e.g. `set` and `taste`, for building syntax files.
+pub fn keywords() -> &'static [&'static str] {
+    static KEYWORDS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    KEYWORDS.get_or_init(|| scanner::KEYWORDS.iter().map(|(name, _)| *name).collect())
+}
+
+/// Compile and run a recipe, returning the compile diagnostics or the first
+/// runtime error instead of reporting them. Any input, however malformed,
+/// gives an error rather than a panic, which makes this the entry point for
+/// fuzzing.
+pub fn try_run(source: &str) -> Result<(), RunError> {
+    let code = compile(source).map_err(RunError::Compile)?;
+    let mut state = State::new(code);
+    state.push_frame(CallFrame::default())?;
+    Ok(state.run()?)
+}
+
+/// Compile a single line typed at the REPL.
+///
+/// A line starting with `Recipe` compiles as a whole recipe. Anything else
+/// becomes the only step of an implicit recipe, and a bare expression is
+/// tasted so its value is printed. Either way a top-level `serve` tastes its
+/// value and stops.
+pub fn compile_line(line: &str, options: CompileOptions) -> Result<Code, Vec<Diagnostic>> {
+    let options = CompileOptions {
+        repl: true,
+        ..options
+    };
+    let line = line.trim_end();
+    let mut terminated = line.to_owned();
+    terminated.push('\0');
+    let prefix = match Scanner::new(&terminated).scan_token().kind {
+        TokenKind::Recipe => return compile_with_options(line, options),
+        TokenKind::Print
+        | TokenKind::DebugPrint
+        | TokenKind::PrettyPrint
+        | TokenKind::If
+        | TokenKind::Else
+        | TokenKind::Return
+        | TokenKind::While
+        | TokenKind::Each
+        | TokenKind::Repeat => "",
+        _ => "taste ",
+    };
+    let source = format!("Recipe\n\nSteps\n//line 1\n    1. {prefix}{line}\n    2. end\n");
+    compile_with_options(&source, options)
+}
@@ -1,76 +1,418 @@
 use std::env;
 use std::io;
+use std::io::IsTerminal;
 use std::io::Write;
+use std::path::Path;
 use std::process::exit;
 
-use compiler::Compiler;
-use error::ChefError;
-use error::InterpretResult;
-use vm::CallFrame;
-use vm::State;
-
-mod code;
-mod common;
-mod compiler;
-mod error;
-mod native_functions;
-mod rules;
-mod scanner;
-mod value;
-mod vm;
-
-fn interpret(source: &str) -> InterpretResult<()> {
-    let compiler = Compiler::new(source);
-    let code = compiler.compile().ok_or(ChefError::Compile)?;
-    let mut state = State::new(code);
+use chef::{CallFrame, ChefError, Code, CompileOptions, Diagnostic, InterpretResult, State, Style};
+
+const USAGE: &str = "Usage: chef [help | version | disassemble [--json]] [--color=<auto|always|never>] [--no-color] [--trace] [--auto-number] [--warn-no-effect] [--deny-warnings] [--optimize] [--dump-constants] [--check-syntax-only [--json]] [--about] [--dump-ast] [--strict-arity] [--round-division] [--allow-env] [--sandbox] [--profile] [--stack-size <n>] [--max-frames <n>] [--recursion-warning <n>] [--max-output-lines <n>] [--explain <code>] [--eval <source> | path]";
+
+const HELP: &str = "Usage: chef [command] [options] [--eval <source> | path]
+
+Runs a recipe file, or starts a REPL when no file is given.
+
+Commands:
+  disassemble                  Print the compiled bytecode instead of running
+  help                         Print this message
+  version                      Print the version of chef
+
+Options:
+  --eval <source>              Run a recipe given on the command line
+  --json                       Print disassembly, or diagnostics with --check-syntax-only, as JSON
+  --color=<auto|always|never>  Colour diagnostics, by default when stderr is a terminal
+  --no-color                   Shorthand for --color=never
+  --trace                      Print each operation to stderr as it runs
+  --auto-number                Accept steps in any numbering
+  --warn-no-effect             Warn about steps that only read a value
+  --deny-warnings              Fail to compile when there are any warnings
+  --optimize                   Collapse common instruction sequences after compiling
+  --dump-constants             Print the constant table instead of running
+  --check-syntax-only          Report diagnostics without running
+  --about                      Print the recipe's About section instead of running
+  --dump-ast                   Print the parsed recipe as a tree instead of running
+  --strict-arity               Error when a native gets the wrong number of arguments
+  --round-division             Floor the result of every 'split'
+  --allow-env                  Let the 'env' utensil read environment variables
+  --sandbox                    Refuse 'include' and utensils that reach the host, such as 'time'
+  --profile                    Print how often each opcode ran when the recipe ends
+  --stack-size <n>             Allow n values on the VM stack, at most 1048576
+  --max-frames <n>             Allow n nested utensil calls, counting the recipe itself, at most 65536
+  --recursion-warning <n>      Warn once calls nest past n% of the frame limit, 0 for never
+  --max-output-lines <n>       Stop with an error rather than taste more than n times
+  --explain <code>             Explain an error code, e.g. E0003
+  -h, --help                   Print this message
+  --version                    Print the version of chef";
+
+#[derive(Default)]
+enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Default, PartialEq)]
+enum Command {
+    #[default]
+    Run,
+    Disassemble,
+    Help,
+    Version,
+}
+
+#[derive(Default)]
+struct Options {
+    command: Command,
+    path: Option<String>,
+    eval: Option<String>,
+    json: bool,
+    color: ColorChoice,
+    trace: bool,
+    auto_number: bool,
+    warn_no_effect: bool,
+    deny_warnings: bool,
+    optimize: bool,
+    dump_constants: bool,
+    check_syntax_only: bool,
+    about: bool,
+    dump_ast: bool,
+    strict_arity: bool,
+    round_division: bool,
+    allow_env: bool,
+    sandbox: bool,
+    profile: bool,
+    stack_size: Option<usize>,
+    max_frames: Option<usize>,
+    recursion_warning: Option<u8>,
+    max_output_lines: Option<usize>,
+    explain: Option<String>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Option<Self> {
+        let mut options = Self::default();
+        let mut args = args.peekable();
+        match args.peek().map(String::as_str) {
+            Some("disassemble") => options.command = Command::Disassemble,
+            Some("help") => options.command = Command::Help,
+            Some("version") => options.command = Command::Version,
+            _ => {}
+        }
+        if options.command != Command::Run {
+            args.next();
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => options.json = true,
+                "--no-color" | "--color=never" => options.color = ColorChoice::Never,
+                "--color=always" => options.color = ColorChoice::Always,
+                "--color=auto" => options.color = ColorChoice::Auto,
+                "--trace" => options.trace = true,
+                "--auto-number" => options.auto_number = true,
+                "--warn-no-effect" => options.warn_no_effect = true,
+                "--deny-warnings" => options.deny_warnings = true,
+                "--optimize" => options.optimize = true,
+                "--dump-constants" => options.dump_constants = true,
+                "--check-syntax-only" => options.check_syntax_only = true,
+                "--about" => options.about = true,
+                "--dump-ast" => options.dump_ast = true,
+                "--strict-arity" => options.strict_arity = true,
+                "--round-division" => options.round_division = true,
+                "--allow-env" => options.allow_env = true,
+                "--sandbox" => options.sandbox = true,
+                "--profile" => options.profile = true,
+                "--stack-size" => {
+                    options.stack_size = Some(bounded(args.next()?, STACK_SIZE_MAX)?);
+                }
+                "--max-frames" => {
+                    options.max_frames = Some(bounded(args.next()?, MAX_FRAMES_MAX)?);
+                }
+                "--recursion-warning" => {
+                    let percent = args
+                        .next()?
+                        .parse()
+                        .ok()
+                        .filter(|&percent| percent <= 100)?;
+                    options.recursion_warning = Some(percent);
+                }
+                "--max-output-lines" => options.max_output_lines = Some(positive(args.next()?)?),
+                "--eval" => options.eval = Some(args.next()?),
+                "--explain" => options.explain = Some(args.next()?),
+                "-h" | "--help" => options.command = Command::Help,
+                "--version" => options.command = Command::Version,
+                flag if flag.starts_with("--") => return None,
+                _ if options.path.is_some() => return None,
+                _ => options.path = Some(arg),
+            }
+        }
+        // Only these two print JSON, so anywhere else it's a usage error
+        let prints_json = options.command == Command::Disassemble || options.check_syntax_only;
+        if options.json && !prints_json {
+            return None;
+        }
+        match (&options.path, &options.eval) {
+            (Some(_), Some(_)) => return None,
+            (None, None) if options.command == Command::Disassemble => return None,
+            _ => {}
+        }
+        Some(options)
+    }
+
+    /// Whether `warnings` fail the compile, as they do under `--deny-warnings`.
+    fn denies(&self, warnings: &[Diagnostic]) -> bool {
+        self.deny_warnings && !warnings.is_empty()
+    }
+
+    fn state(&self, code: Code) -> State {
+        let mut state = State::new(code)
+            .with_style(self.style())
+            .with_trace(self.trace)
+            .with_strict_arity(self.strict_arity)
+            .with_round_division(self.round_division)
+            .with_allow_env(self.allow_env)
+            .with_sandbox(self.sandbox)
+            .with_profile(self.profile);
+        if let Some(stack_size) = self.stack_size {
+            state = state.with_stack_size(stack_size);
+        }
+        if let Some(max_frames) = self.max_frames {
+            state = state.with_max_frames(max_frames);
+        }
+        if let Some(percent) = self.recursion_warning {
+            state = state.with_recursion_warning(percent);
+        }
+        if let Some(max_output_lines) = self.max_output_lines {
+            state = state.with_max_output_lines(max_output_lines);
+        }
+        state
+    }
+
+    fn style(&self) -> Style {
+        match self.color {
+            ColorChoice::Auto => Style::new(io::stderr().is_terminal()),
+            ColorChoice::Always => Style::new(true),
+            ColorChoice::Never => Style::new(false),
+        }
+    }
+}
+
+fn positive(arg: String) -> Option<usize> {
+    arg.parse().ok().filter(|&value| value > 0)
+}
+
+// Both are allocated up front, so a typo can't ask for gigabytes
+const STACK_SIZE_MAX: usize = 1 << 20;
+const MAX_FRAMES_MAX: usize = 1 << 16;
+
+fn bounded(arg: String, max: usize) -> Option<usize> {
+    positive(arg).filter(|&value| value <= max)
+}
+
+fn compile_source(
+    source: &str,
+    path: Option<&Path>,
+    options: &Options,
+) -> Result<Code, Vec<Diagnostic>> {
+    let compile_options = CompileOptions {
+        auto_number: options.auto_number,
+        warn_no_effect: options.warn_no_effect,
+        sandbox: options.sandbox,
+        ..CompileOptions::default()
+    };
+    match (path, &options.eval) {
+        (Some(path), _) => chef::compile_with_includes(source, path, compile_options),
+        (None, Some(_)) => chef::compile_with_options(source, compile_options),
+        (None, None) => chef::compile_line(source, compile_options),
+    }
+}
+
+fn compile(source: &str, path: Option<&Path>, options: &Options) -> InterpretResult<Code> {
+    let style = options.style();
+    match compile_source(source, path, options) {
+        Ok(mut code) => {
+            if options.optimize {
+                chef::optimize(&mut code);
+            }
+            for warning in &code.warnings {
+                eprintln!("{}", warning.render(style));
+            }
+            match options.denies(&code.warnings) {
+                true => {
+                    report_error(&ChefError::Compile, style);
+                    Err(ChefError::Compile)
+                }
+                false => Ok(code),
+            }
+        }
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic.render(style));
+            }
+            report_error(&ChefError::Compile, style);
+            Err(ChefError::Compile)
+        }
+    }
+}
+
+/// Print an error headed by its code, e.g. `error[E0003]: Stack overflow.`,
+/// which `--explain` looks up.
+fn report_error(err: &ChefError, style: Style) {
+    let code = style.error(&format!("error[{}]", err.code()));
+    eprintln!("{code}: {err}");
+}
+
+fn interpret(source: &str, path: Option<&Path>, options: &Options) -> InterpretResult<()> {
+    let style = options.style();
+    let code = compile(source, path, options)?;
+    let mut state = options.state(code);
     state.push_frame(CallFrame::default())?;
     let result = state.run();
     if let Err(err) = &result {
-        eprintln!("{err}");
+        report_error(err, style);
         state.stack_error();
     }
+    if options.profile {
+        print_profile(&state);
+    }
     result
 }
 
+fn print_profile(state: &State) {
+    const BAR_WIDTH: u64 = 40;
+    let profile = state.profile();
+    let Some(&(_, most)) = profile.first() else {
+        return;
+    };
+    for (opcode, count) in profile {
+        let bar = "#".repeat((count * BAR_WIDTH).div_ceil(most) as usize);
+        eprintln!("{: <14} {count:>10}  {bar}", format!("{opcode:?}"));
+    }
+}
+
 fn main() {
-    let args = env::args().collect::<Vec<String>>();
-    match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
-        _ => {
-            eprintln!("Usage: chef [path]");
-            exit(64)
+    let Some(options) = Options::parse(env::args().skip(1)) else {
+        eprintln!("{USAGE}");
+        exit(64)
+    };
+    if let Some(code) = &options.explain {
+        match chef::explain(code) {
+            Some(explanation) => return println!("{explanation}"),
+            None => {
+                eprintln!("Unknown error code '{code}'.");
+                exit(64)
+            }
         }
     }
+    match options.command {
+        Command::Help => return println!("{HELP}"),
+        Command::Version => return println!("chef {}", env!("CARGO_PKG_VERSION")),
+        Command::Run | Command::Disassemble => {}
+    }
+    match (&options.path, &options.eval) {
+        (Some(path), _) => run_file(path, &options),
+        (None, Some(source)) => run_source(source, None, &options),
+        (None, None) => repl(&options),
+    }
 }
 
-fn repl() {
+fn repl(options: &Options) {
     let mut buf = String::new();
     loop {
         buf.clear();
         print!("chef > ");
         io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut buf).unwrap();
-        buf.push('\0');
-        let _ = interpret(&buf);
+        match io::stdin().read_line(&mut buf) {
+            Ok(0) | Err(_) => {
+                println!();
+                break;
+            }
+            Ok(_) if buf.trim().is_empty() => continue,
+            Ok(_) => {
+                let _ = interpret(&buf, None, options);
+            }
+        }
     }
 }
 
-fn run_file(path: &str) {
+fn run_file(path: &str, options: &Options) {
     if !path.ends_with(".chef") && !path.ends_with(".recipe") {
         eprintln!("Source code file extension should be `.chef` or `.recipe`.");
         exit(74);
     }
-    let Ok(mut source) = std::fs::read_to_string(path) else {
+    let Ok(source) = std::fs::read_to_string(path) else {
         eprintln!("Could not read file.");
         exit(74);
     };
-    source.push('\0');
+
+    run_source(&source, Some(Path::new(path)), options);
+}
+
+fn run_source(source: &str, path: Option<&Path>, options: &Options) {
+    let result = match options.command {
+        Command::Run if options.dump_constants => dump_constants(source, path, options),
+        Command::Run if options.check_syntax_only => check_syntax(source, path, options),
+        Command::Run if options.about => about(source, path, options),
+        Command::Run if options.dump_ast => dump_ast(source, path, options),
+        Command::Run => interpret(source, path, options),
+        Command::Disassemble => disassemble(source, path, options),
+        Command::Help | Command::Version => unreachable!("Printed without compiling."),
+    };
 
     // unix sysexits.h exit codes
-    match interpret(&source) {
+    match result {
         Err(ChefError::Compile) => exit(65),
         Ok(_) => exit(0),
         Err(_) => exit(70),
     }
 }
+
+fn disassemble(source: &str, path: Option<&Path>, options: &Options) -> InterpretResult<()> {
+    let code = compile(source, path, options)?;
+    match options.json {
+        true => println!("{}", code.disassemble_json()),
+        false => code.disassemble(),
+    }
+    Ok(())
+}
+
+fn about(source: &str, path: Option<&Path>, options: &Options) -> InterpretResult<()> {
+    let code = compile(source, path, options)?;
+    for (key, value) in &code.metadata {
+        println!("{key}: {value}");
+    }
+    Ok(())
+}
+
+fn dump_ast(source: &str, path: Option<&Path>, options: &Options) -> InterpretResult<()> {
+    compile(source, path, options)?;
+    println!("{}", chef::dump_ast(source));
+    Ok(())
+}
+
+fn check_syntax(source: &str, path: Option<&Path>, options: &Options) -> InterpretResult<()> {
+    if !options.json {
+        return compile(source, path, options).map(drop);
+    }
+    let (diagnostics, has_errors) = match compile_source(source, path, options) {
+        Ok(code) => {
+            let has_errors = options.denies(&code.warnings);
+            (code.warnings, has_errors)
+        }
+        Err(diagnostics) => (diagnostics, true),
+    };
+    println!("{}", chef::diagnostics_json(&diagnostics));
+    match has_errors {
+        true => Err(ChefError::Compile),
+        false => Ok(()),
+    }
+}
+
+fn dump_constants(source: &str, path: Option<&Path>, options: &Options) -> InterpretResult<()> {
+    let code = compile(source, path, options)?;
+    for (index, constant) in code.constants[..code.constants_count].iter().enumerate() {
+        println!("{index:0>4}  {constant}");
+    }
+    Ok(())
+}
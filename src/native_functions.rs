@@ -1,13 +1,99 @@
+use std::env;
+use std::io;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::error::{ChefError, InterpretResult};
 use crate::value::Value;
 
-pub type NativeFunction = fn(arg_count: u8, ip: usize) -> Value;
+pub type NativeFn = fn(arguments: &[Value]) -> InterpretResult<Value>;
 
-const NATIVE_FUNCTION_COUNT: usize = 1;
+/// Access to the host a native needs, which `State` must allow before
+/// calling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Env,
+    Clock,
+    Stdin,
+}
+
+impl Capability {
+    /// What the capability does, as named in errors.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Env => "Reading environment variables",
+            Self::Clock => "Reading the clock",
+            Self::Stdin => "Reading input",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NativeFunction {
+    pub arity: u8,
+    pub function: NativeFn,
+    pub capability: Option<Capability>,
+}
+
+// Derived comparison of the function pointer is rejected by rustc, as the
+// same function can have different addresses
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::fn_addr_eq(self.function, other.function)
+    }
+}
 
-pub fn declare_native_functions() -> [(&'static str, NativeFunction); NATIVE_FUNCTION_COUNT] {
-    [("time", current_time_s)]
+const NATIVE_FUNCTION_COUNT: usize = 22;
+
+/// Every native by name, in the order `Opcode::Native` indexes them.
+pub const NATIVE_FUNCTIONS: [(&str, NativeFunction); NATIVE_FUNCTION_COUNT] = [
+    ("time", restricted(0, current_time_s, Capability::Clock)),
+    ("sum", native(1, sum)),
+    ("product", native(1, product)),
+    ("abs_diff", native(2, abs_diff)),
+    ("sign", native(1, sign)),
+    ("contains", native(2, contains)),
+    ("upper", native(1, upper)),
+    ("lower", native(1, lower)),
+    ("trim", native(1, trim)),
+    ("split_on", native(2, split_on)),
+    ("index_of", native(2, index_of)),
+    ("count_occurrences", native(2, count_occurrences)),
+    ("is_number", native(1, is_number)),
+    ("is_string", native(1, is_string)),
+    ("is_nil", native(1, is_nil)),
+    ("is_boolean", native(1, is_boolean)),
+    (
+        "clock_millis",
+        restricted(0, clock_millis, Capability::Clock),
+    ),
+    ("first", native(1, first)),
+    ("last", native(1, last)),
+    ("rest", native(1, rest)),
+    ("env", restricted(1, env_var, Capability::Env)),
+    ("read_line", restricted(0, read_line, Capability::Stdin)),
+];
+
+const fn native(arity: u8, function: NativeFn) -> NativeFunction {
+    NativeFunction {
+        arity,
+        function,
+        capability: None,
+    }
+}
+
+const fn restricted(arity: u8, function: NativeFn, capability: Capability) -> NativeFunction {
+    NativeFunction {
+        arity,
+        function,
+        capability: Some(capability),
+    }
+}
+
+fn argument(arguments: &[Value], index: usize) -> InterpretResult<&Value> {
+    arguments.get(index).ok_or(ChefError::FunctionArity(
+        index as u8 + 1,
+        arguments.len() as u8,
+    ))
 }
 
 fn current_time() -> Duration {
@@ -17,6 +103,162 @@ fn current_time() -> Duration {
         .expect("Time went backwards")
 }
 
-fn current_time_s(_: u8, _: usize) -> Value {
-    Value::Number(current_time().as_secs_f64().floor())
+fn current_time_s(_: &[Value]) -> InterpretResult<Value> {
+    Ok(Value::Number(current_time().as_secs_f64().floor()))
+}
+
+// Fractional, unlike `time`, so short steps can be timed
+fn clock_millis(_: &[Value]) -> InterpretResult<Value> {
+    Ok(Value::Number(current_time().as_secs_f64() * 1000.0))
+}
+
+fn sum(arguments: &[Value]) -> InterpretResult<Value> {
+    let mut total = 0.0;
+    for element in argument(arguments, 0)?.as_list()? {
+        total += element.as_number()?;
+    }
+    Ok(Value::Number(total))
+}
+
+fn product(arguments: &[Value]) -> InterpretResult<Value> {
+    let mut total = 1.0;
+    for element in argument(arguments, 0)?.as_list()? {
+        total *= element.as_number()?;
+    }
+    Ok(Value::Number(total))
+}
+
+fn abs_diff(arguments: &[Value]) -> InterpretResult<Value> {
+    let a = argument(arguments, 0)?.as_number()?;
+    let b = argument(arguments, 1)?.as_number()?;
+    Ok(Value::Number((a - b).abs()))
+}
+
+fn sign(arguments: &[Value]) -> InterpretResult<Value> {
+    let number = argument(arguments, 0)?.as_number()?;
+    let sign = match number {
+        n if n > 0.0 => 1.0,
+        n if n < 0.0 => -1.0,
+        _ => 0.0,
+    };
+    Ok(Value::Number(sign))
+}
+
+fn contains(arguments: &[Value]) -> InterpretResult<Value> {
+    let haystack = argument(arguments, 0)?.as_string()?;
+    let needle = argument(arguments, 1)?.as_string()?;
+    Ok(Value::Boolean(haystack.contains(needle)))
+}
+
+// Nil for a variable that is unset or isn't valid unicode
+fn env_var(arguments: &[Value]) -> InterpretResult<Value> {
+    let name = argument(arguments, 0)?.as_string()?;
+    match env::var(name) {
+        Ok(value) => Ok(Value::String(value.into())),
+        Err(_) => Ok(Value::Nil),
+    }
+}
+
+// Without the line ending, or nil once input has run out
+fn read_line(_: &[Value]) -> InterpretResult<Value> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => Ok(Value::Nil),
+        Ok(_) => {
+            let length = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(length);
+            Ok(Value::String(line.into()))
+        }
+    }
+}
+
+fn first(arguments: &[Value]) -> InterpretResult<Value> {
+    let elements = argument(arguments, 0)?.as_list()?;
+    elements.first().cloned().ok_or(ChefError::OutOfBounds)
+}
+
+fn last(arguments: &[Value]) -> InterpretResult<Value> {
+    let elements = argument(arguments, 0)?.as_list()?;
+    elements.last().cloned().ok_or(ChefError::OutOfBounds)
+}
+
+// Empty for an empty list, so recursing on `rest` stops without an error
+fn rest(arguments: &[Value]) -> InterpretResult<Value> {
+    let elements = argument(arguments, 0)?.as_list()?;
+    Ok(Value::List(elements.iter().skip(1).cloned().collect()))
+}
+
+fn upper(arguments: &[Value]) -> InterpretResult<Value> {
+    let text = argument(arguments, 0)?.as_string()?;
+    Ok(Value::String(text.to_uppercase().into()))
+}
+
+fn lower(arguments: &[Value]) -> InterpretResult<Value> {
+    let text = argument(arguments, 0)?.as_string()?;
+    Ok(Value::String(text.to_lowercase().into()))
+}
+
+fn trim(arguments: &[Value]) -> InterpretResult<Value> {
+    let text = argument(arguments, 0)?.as_string()?;
+    Ok(Value::String(text.trim().into()))
+}
+
+fn split_on(arguments: &[Value]) -> InterpretResult<Value> {
+    let text = argument(arguments, 0)?.as_string()?;
+    let separator = argument(arguments, 1)?.as_string()?;
+    // An empty separator splits into characters rather than around every gap
+    let parts = match separator.is_empty() {
+        true => text
+            .chars()
+            .map(|char| Value::String(char.to_string().into()))
+            .collect(),
+        false => text
+            .split(separator)
+            .map(|part| Value::String(part.into()))
+            .collect(),
+    };
+    Ok(Value::List(parts))
+}
+
+/// Character (not byte) index of the first match, or -1 when absent.
+fn index_of(arguments: &[Value]) -> InterpretResult<Value> {
+    let haystack = argument(arguments, 0)?.as_string()?;
+    let needle = argument(arguments, 1)?.as_string()?;
+    let index = match haystack.find(needle) {
+        Some(byte_index) => haystack[..byte_index].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::Number(index))
+}
+
+/// Number of non-overlapping matches, so "aaaa" holds "aa" twice.
+fn count_occurrences(arguments: &[Value]) -> InterpretResult<Value> {
+    let haystack = argument(arguments, 0)?.as_string()?;
+    let needle = argument(arguments, 1)?.as_string()?;
+    // An empty needle would otherwise match between every character
+    let count = match needle.is_empty() {
+        true => 0,
+        false => haystack.matches(needle).count(),
+    };
+    Ok(Value::Number(count as f64))
+}
+
+fn is_number(arguments: &[Value]) -> InterpretResult<Value> {
+    let value = argument(arguments, 0)?;
+    Ok(Value::Boolean(matches!(value, Value::Number(_))))
+}
+
+fn is_string(arguments: &[Value]) -> InterpretResult<Value> {
+    let value = argument(arguments, 0)?;
+    Ok(Value::Boolean(matches!(value, Value::String(_))))
+}
+
+fn is_nil(arguments: &[Value]) -> InterpretResult<Value> {
+    let value = argument(arguments, 0)?;
+    Ok(Value::Boolean(matches!(value, Value::Nil)))
+}
+
+fn is_boolean(arguments: &[Value]) -> InterpretResult<Value> {
+    let value = argument(arguments, 0)?;
+    Ok(Value::Boolean(matches!(value, Value::Boolean(_))))
 }
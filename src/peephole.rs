@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+
+use crate::code::{Code, Opcode};
+use crate::common::U8_MAX_USIZE;
+use crate::value::Value;
+
+/// Collapse common instruction sequences in compiled code:
+///
+/// - `True` or `False` followed by `Not` becomes the opposite literal
+/// - a run of `Pop`s becomes one `PopN`
+/// - `Nop`s are removed
+///
+/// Jump offsets, utensil entry points and line numbers follow the moved
+/// instructions. A sequence is left alone if a jump lands inside it, and
+/// code that can't be decoded is left alone entirely.
+pub fn optimize(code: &mut Code) {
+    let Some(instructions) = instructions(code) else {
+        return;
+    };
+    let Some(targets) = jump_targets(code, &instructions) else {
+        return;
+    };
+    let is_fusable = |index: usize, opcode: Opcode| {
+        instructions
+            .get(index)
+            .is_some_and(|&(offset, next)| next == opcode && !targets.contains(&offset))
+    };
+
+    let mut bytes = Vec::with_capacity(code.bytes.len());
+    let mut lines = Vec::with_capacity(code.lines.len());
+    // Where each old instruction, and the end of the code, moved to
+    let mut offsets = vec![0; code.bytes.len() + 1];
+    let mut jumps = Vec::new();
+    let mut index = 0;
+    while index < instructions.len() {
+        let (offset, opcode) = instructions[index];
+        let line = code.lines[offset];
+        offsets[offset] = bytes.len();
+        let fused = match opcode {
+            Opcode::Nop => 1,
+            Opcode::True | Opcode::False if is_fusable(index + 1, Opcode::Not) => {
+                let literal = match opcode {
+                    Opcode::True => Opcode::False,
+                    _ => Opcode::True,
+                };
+                bytes.push(literal as u8);
+                lines.push(line);
+                2
+            }
+            Opcode::Pop if is_fusable(index + 1, Opcode::Pop) => {
+                let mut count = 2;
+                while count < U8_MAX_USIZE && is_fusable(index + count, Opcode::Pop) {
+                    count += 1;
+                }
+                bytes.extend([Opcode::PopN as u8, count as u8]);
+                lines.extend([line; 2]);
+                count
+            }
+            _ => {
+                if let Some(target) = jump_target(code, offset, opcode) {
+                    jumps.push((bytes.len(), opcode, target));
+                }
+                let end = offset + 1 + opcode.operand_count();
+                bytes.extend(&code.bytes[offset..end]);
+                lines.extend(&code.lines[offset..end]);
+                1
+            }
+        };
+        for &(fused_offset, _) in &instructions[index + 1..index + fused] {
+            offsets[fused_offset] = bytes.len();
+        }
+        index += fused;
+    }
+    offsets[code.bytes.len()] = bytes.len();
+
+    // Code only shrinks, so every new distance still fits in a u16
+    for (offset, opcode, target) in jumps {
+        let after = offset + 1 + opcode.operand_count();
+        let distance = match opcode {
+            Opcode::Loop => after - offsets[target],
+            _ => offsets[target] - after,
+        };
+        let [low, high] = (distance as u16).to_le_bytes();
+        bytes[offset + 1] = low;
+        bytes[offset + 2] = high;
+    }
+    for constant in &mut code.constants[..code.constants_count] {
+        if let Value::Function(function) = constant {
+            function.ip_start = offsets[function.ip_start];
+        }
+    }
+    // Functions are keyed by their entry point, which just moved
+    code.reindex_constants();
+    code.bytes = bytes;
+    code.lines = lines;
+}
+
+/// Each instruction's offset and opcode, or `None` if an opcode is unknown
+/// or its operands are missing.
+fn instructions(code: &Code) -> Option<Vec<(usize, Opcode)>> {
+    if code.lines.len() != code.bytes.len() || code.constants_count > code.constants.len() {
+        return None;
+    }
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < code.bytes.len() {
+        let opcode = Opcode::from_byte(code.bytes[offset])?;
+        let end = offset + 1 + opcode.operand_count();
+        code.bytes.get(offset + 1..end)?;
+        instructions.push((offset, opcode));
+        offset = end;
+    }
+    Some(instructions)
+}
+
+/// Every offset execution can arrive at other than from the instruction
+/// before it, or `None` if one of them isn't the start of an instruction.
+fn jump_targets(code: &Code, instructions: &[(usize, Opcode)]) -> Option<HashSet<usize>> {
+    let mut starts: HashSet<_> = instructions.iter().map(|&(offset, _)| offset).collect();
+    starts.insert(code.bytes.len());
+    let jumps = instructions
+        .iter()
+        .filter(|&&(_, opcode)| is_jump(opcode))
+        .map(|&(offset, opcode)| jump_target(code, offset, opcode));
+    let utensils = code.constants[..code.constants_count]
+        .iter()
+        .filter_map(|constant| match constant {
+            Value::Function(function) => Some(Some(function.ip_start)),
+            _ => None,
+        });
+    jumps
+        .chain(utensils)
+        .map(|target| target.filter(|target| starts.contains(target)))
+        .collect()
+}
+
+fn is_jump(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::Loop
+    )
+}
+
+fn jump_target(code: &Code, offset: usize, opcode: Opcode) -> Option<usize> {
+    let distance = || u16::from_le_bytes([code.bytes[offset + 1], code.bytes[offset + 2]]) as usize;
+    match opcode {
+        Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue => Some(offset + 3 + distance()),
+        Opcode::Loop => (offset + 3).checked_sub(distance()),
+        _ => None,
+    }
+}
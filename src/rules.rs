@@ -7,7 +7,7 @@ pub enum Precedence {
     Or,         // or
     And,        // and
     Equality,   // == !=
-    Comparison, // < > <= >=
+    Comparison, // < > <= >= in
     Term,       // + -
     Factor,     // * /
     Unary,      // ! -
@@ -28,6 +28,8 @@ pub enum ParseFunctionKind {
     And,
     Or,
     Call,
+    List,
+    Index,
 }
 
 pub struct ParseRule {
@@ -65,6 +67,16 @@ impl Precedence {
                 infix: ParseFunctionKind::None,
                 precedence: Precedence::None,
             },
+            TokenKind::LeftBracket => ParseRule {
+                prefix: ParseFunctionKind::List,
+                infix: ParseFunctionKind::Index,
+                precedence: Precedence::Call,
+            },
+            TokenKind::RightBracket => ParseRule {
+                prefix: ParseFunctionKind::None,
+                infix: ParseFunctionKind::None,
+                precedence: Precedence::None,
+            },
             TokenKind::RightParen => ParseRule {
                 prefix: ParseFunctionKind::None,
                 infix: ParseFunctionKind::None,
@@ -110,6 +122,11 @@ impl Precedence {
                 infix: ParseFunctionKind::Binary,
                 precedence: Precedence::Factor,
             },
+            TokenKind::FloorSlash => ParseRule {
+                prefix: ParseFunctionKind::None,
+                infix: ParseFunctionKind::Binary,
+                precedence: Precedence::Factor,
+            },
             TokenKind::Star => ParseRule {
                 prefix: ParseFunctionKind::None,
                 infix: ParseFunctionKind::Binary,
@@ -191,7 +208,7 @@ impl Precedence {
                 precedence: Precedence::None,
             },
             TokenKind::If => ParseRule {
-                prefix: ParseFunctionKind::Grouping,
+                prefix: ParseFunctionKind::None,
                 infix: ParseFunctionKind::None,
                 precedence: Precedence::None,
             },
@@ -205,7 +222,7 @@ impl Precedence {
                 infix: ParseFunctionKind::Or,
                 precedence: Precedence::Or,
             },
-            TokenKind::Print => ParseRule {
+            TokenKind::Print | TokenKind::DebugPrint | TokenKind::PrettyPrint => ParseRule {
                 prefix: ParseFunctionKind::None,
                 infix: ParseFunctionKind::None,
                 precedence: Precedence::None,
@@ -221,7 +238,32 @@ impl Precedence {
                 precedence: Precedence::None,
             },
             TokenKind::While => ParseRule {
-                prefix: ParseFunctionKind::Grouping,
+                prefix: ParseFunctionKind::None,
+                infix: ParseFunctionKind::None,
+                precedence: Precedence::None,
+            },
+            TokenKind::Each => ParseRule {
+                prefix: ParseFunctionKind::None,
+                infix: ParseFunctionKind::None,
+                precedence: Precedence::None,
+            },
+            TokenKind::In => ParseRule {
+                prefix: ParseFunctionKind::None,
+                infix: ParseFunctionKind::Binary,
+                precedence: Precedence::Comparison,
+            },
+            TokenKind::Repeat => ParseRule {
+                prefix: ParseFunctionKind::None,
+                infix: ParseFunctionKind::None,
+                precedence: Precedence::None,
+            },
+            TokenKind::Times => ParseRule {
+                prefix: ParseFunctionKind::None,
+                infix: ParseFunctionKind::None,
+                precedence: Precedence::None,
+            },
+            TokenKind::Then => ParseRule {
+                prefix: ParseFunctionKind::None,
                 infix: ParseFunctionKind::None,
                 precedence: Precedence::None,
             },
@@ -240,6 +282,11 @@ impl Precedence {
                 infix: ParseFunctionKind::None,
                 precedence: Precedence::None,
             },
+            TokenKind::AboutHeader | TokenKind::AboutEntry => ParseRule {
+                prefix: ParseFunctionKind::None,
+                infix: ParseFunctionKind::None,
+                precedence: Precedence::None,
+            },
             TokenKind::IngredientsHeader => ParseRule {
                 prefix: ParseFunctionKind::None,
                 infix: ParseFunctionKind::None,
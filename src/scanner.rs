@@ -1,44 +1,70 @@
 use std::collections::HashMap;
+
+/// Reserved words and the tokens they scan as. Ingredient and utensil names
+/// are identifiers rather than keywords, so are added in `Scanner::new`.
+pub const KEYWORDS: &[(&str, TokenKind)] = &[
+    ("compliments", TokenKind::And),
+    ("and", TokenKind::ParameterAnd),
+    ("add", TokenKind::Plus),
+    ("now", TokenKind::BareFunctionInvocation),
+    ("minus", TokenKind::Minus),
+    ("check", TokenKind::If),
+    ("with", TokenKind::With),
+    ("not", TokenKind::Bang),
+    ("isnt", TokenKind::BangEqual),
+    ("split", TokenKind::Slash),
+    ("floor_split", TokenKind::FloorSlash),
+    ("multiply", TokenKind::Star),
+    ("above", TokenKind::Greater),
+    ("below", TokenKind::Less),
+    ("otherwise", TokenKind::Else),
+    ("false", TokenKind::False),
+    ("nil", TokenKind::Nil),
+    ("or", TokenKind::Or),
+    ("is", TokenKind::EqualEqual),
+    ("equals", TokenKind::EqualEqual),
+    ("not_equals", TokenKind::BangEqual),
+    ("greater_than", TokenKind::Greater),
+    ("less_than", TokenKind::Less),
+    ("to", TokenKind::Equal),
+    ("set", TokenKind::Var),
+    ("taste", TokenKind::Print),
+    ("debug_taste", TokenKind::DebugPrint),
+    ("garnish", TokenKind::PrettyPrint),
+    ("serve", TokenKind::Return),
+    ("true", TokenKind::True),
+    ("while", TokenKind::While),
+    ("mix_while", TokenKind::While),
+    ("then", TokenKind::Then),
+    ("each", TokenKind::Each),
+    ("in", TokenKind::In),
+    ("repeat", TokenKind::Repeat),
+    ("times", TokenKind::Times),
+    ("end", TokenKind::RightBrace),
+    ("Recipe", TokenKind::Recipe),
+    ("About", TokenKind::AboutHeader),
+    ("Ingredients", TokenKind::IngredientsHeader),
+    ("Utensils", TokenKind::UtensilsHeader),
+    ("Steps", TokenKind::StepsHeader),
+];
+
 pub struct Scanner<'src> {
     identifiers: HashMap<&'static str, TokenKind>,
     source: &'src str,
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,
+    column: usize,
+    tab_width: usize,
+    // Inside `About`, where each line is scanned whole as one entry
+    in_about: bool,
+    file: Option<&'src str>,
 }
 
 impl<'src> Scanner<'src> {
     pub fn new(source: &'src str) -> Self {
-        let mut identifiers = HashMap::new();
-        identifiers.insert("compliments", TokenKind::And);
-        identifiers.insert("and", TokenKind::ParameterAnd);
-        identifiers.insert("add", TokenKind::Plus);
-        identifiers.insert("now", TokenKind::BareFunctionInvocation);
-        identifiers.insert("minus", TokenKind::Minus);
-        identifiers.insert("check", TokenKind::If);
-        identifiers.insert("with", TokenKind::With);
-        identifiers.insert("not", TokenKind::Bang);
-        identifiers.insert("isnt", TokenKind::BangEqual);
-        identifiers.insert("split", TokenKind::Slash);
-        identifiers.insert("multiply", TokenKind::Star);
-        identifiers.insert("above", TokenKind::Greater);
-        identifiers.insert("below", TokenKind::Less);
-        identifiers.insert("otherwise", TokenKind::Else);
-        identifiers.insert("false", TokenKind::False);
-        identifiers.insert("nil", TokenKind::Nil);
-        identifiers.insert("or", TokenKind::Or);
-        identifiers.insert("is", TokenKind::EqualEqual);
-        identifiers.insert("to", TokenKind::Equal);
-        identifiers.insert("set", TokenKind::Var);
-        identifiers.insert("taste", TokenKind::Print);
-        identifiers.insert("serve", TokenKind::Return);
-        identifiers.insert("true", TokenKind::True);
-        identifiers.insert("while", TokenKind::While);
-        identifiers.insert("end", TokenKind::RightBrace);
-        identifiers.insert("Recipe", TokenKind::Recipe);
-        identifiers.insert("Ingredients", TokenKind::IngredientsHeader);
-        identifiers.insert("Utensils", TokenKind::UtensilsHeader);
-        identifiers.insert("Steps", TokenKind::StepsHeader);
+        let mut identifiers: HashMap<_, _> = KEYWORDS.iter().copied().collect();
 
         identifiers.insert("egg", TokenKind::VarIdent);
         identifiers.insert("flour", TokenKind::VarIdent);
@@ -58,9 +84,30 @@ impl<'src> Scanner<'src> {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            column: 1,
+            tab_width: 4,
+            in_about: false,
+            file: None,
         }
     }
 
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width.max(1);
+    }
+
+    /// The 1-based column of the current token, with each tab moving on to
+    /// the next tab stop as an editor would show it.
+    fn column(&self) -> usize {
+        let column = self.source[self.line_start..self.start]
+            .chars()
+            .fold(0, |column, char| match char {
+                '\t' => (column / self.tab_width + 1) * self.tab_width,
+                _ => column + 1,
+            });
+        column + 1
+    }
+
     fn advance(&mut self) -> u8 {
         let byte = self.source.as_bytes()[self.current];
         self.current += 1;
@@ -74,17 +121,35 @@ impl<'src> Scanner<'src> {
     pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
         self.start = self.current;
+        self.column = self.column();
         if self.is_at_end() {
             return self.make_token(TokenKind::Eof);
         }
+        if self.in_about {
+            if let Some(token) = self.make_about_token() {
+                return token;
+            }
+        }
         let byte = self.advance();
         match byte {
             b',' => self.make_token(TokenKind::Comma),
             b'(' => self.make_token(TokenKind::LeftParen),
             b')' => self.make_token(TokenKind::RightParen),
+            b'[' => self.make_token(TokenKind::LeftBracket),
+            b']' => self.make_token(TokenKind::RightBracket),
             b'"' => self.make_string_token(),
             b if b.is_ascii_digit() => self.make_number_token(),
             b if is_alpha(b) => self.make_identifier_token(),
+            b if !b.is_ascii() => {
+                // Consume the whole character so tokens stay on char boundaries
+                self.current = self.start;
+                let char = self.peek_char();
+                self.current += char.len_utf8();
+                match char.is_alphabetic() {
+                    true => self.make_identifier_token(),
+                    false => self.make_error_token("Unexpected character."),
+                }
+            }
             _ => self.make_error_token("Unexpected character."),
         }
     }
@@ -98,6 +163,8 @@ impl<'src> Scanner<'src> {
             kind,
             lexeme: self.lexeme(),
             line: self.line,
+            column: self.column,
+            file: self.file,
         }
     }
 
@@ -106,28 +173,57 @@ impl<'src> Scanner<'src> {
             kind: TokenKind::Error,
             lexeme: message,
             line: self.line,
+            column: self.column,
+            file: self.file,
         }
     }
 
     fn make_identifier_token(&mut self) -> Token<'src> {
         loop {
             let byte = self.peek();
-            if !byte.is_ascii_digit() && !is_alpha(byte) {
-                break;
+            if byte.is_ascii_digit() || is_alpha(byte) {
+                self.current += 1;
+                continue;
             }
-            self.current += 1;
+            if !byte.is_ascii() && self.peek_char().is_alphabetic() {
+                self.current += self.peek_char().len_utf8();
+                continue;
+            }
+            break;
+        }
+        if !self.lexeme().is_ascii() {
+            return self.make_error_token("Non-ASCII identifiers are not supported.");
         }
         let kind = match self.identifiers.get(self.lexeme()) {
             Some(kind) => *kind,
             None => TokenKind::Ident,
         };
+        self.in_about = kind == TokenKind::AboutHeader;
         self.make_token(kind)
     }
 
+    /// The rest of the line as one `About` entry, or `None` once the line
+    /// starts the next section.
+    fn make_about_token(&mut self) -> Option<Token<'src>> {
+        let rest = &self.source[self.current..];
+        let line = &rest[..rest.find(['\n', '\0']).unwrap_or(rest.len())];
+        let word = line.split_whitespace().next().unwrap_or_default();
+        if let Some(
+            TokenKind::IngredientsHeader | TokenKind::UtensilsHeader | TokenKind::StepsHeader,
+        ) = self.identifiers.get(word)
+        {
+            self.in_about = false;
+            return None;
+        }
+        self.current += line.trim_end().len();
+        Some(self.make_token(TokenKind::AboutEntry))
+    }
+
     fn make_string_token(&mut self) -> Token<'src> {
         while self.peek() != b'"' && !self.is_at_end() {
             if self.advance() == b'\n' {
-                self.line += 1
+                self.line += 1;
+                self.line_start = self.current;
             }
         }
         if self.is_at_end() {
@@ -141,6 +237,7 @@ impl<'src> Scanner<'src> {
         while self.peek().is_ascii_digit() {
             self.current += 1
         }
+        // Only the closing '\0' has no next byte, so a '.' always does
         let Some(next) = self.peek_next() else {
             return self.make_token(TokenKind::Number);
         };
@@ -162,6 +259,10 @@ impl<'src> Scanner<'src> {
         self.source.as_bytes()[self.current]
     }
 
+    fn peek_char(&self) -> char {
+        self.source[self.current..].chars().next().unwrap()
+    }
+
     fn peek_next(&self) -> Option<u8> {
         match self.current + 1 < self.source.len() {
             true => Some(self.source.as_bytes()[self.current + 1]),
@@ -177,19 +278,61 @@ impl<'src> Scanner<'src> {
                 b'\n' => {
                     self.line += 1;
                     self.current += 1;
+                    self.line_start = self.current;
                 }
                 b'/' => match self.peek_next() {
                     Some(b'/') => {
-                        while self.peek() != b'\n' && !self.is_at_end() {
-                            self.current += 1
-                        }
+                        self.line_directive();
+                        self.skip_line_comment();
                     }
                     Some(_) | None => return,
                 },
+                b'#' => self.skip_line_comment(),
                 _ => break,
             }
         }
     }
+
+    /// Honour a `//line N "file"` comment, which makes the following line
+    /// report as line `N` of another file. Without a file name the line is
+    /// back in the source being scanned.
+    fn line_directive(&mut self) {
+        let source = self.source;
+        let comment = &source[self.current..];
+        let comment = &comment[..comment.find('\n').unwrap_or(comment.len())];
+        let Some(directive) = comment.strip_prefix("//line ") else {
+            return;
+        };
+        let mut parts = directive.trim().splitn(2, ' ');
+        let Some(Ok(line)) = parts.next().map(str::parse::<usize>) else {
+            return;
+        };
+        if line == 0 {
+            return;
+        }
+        let file = match parts.next() {
+            Some(file) => {
+                let file = file.trim();
+                match file
+                    .strip_prefix('"')
+                    .and_then(|file| file.strip_suffix('"'))
+                {
+                    Some(file) => Some(file),
+                    None => return,
+                }
+            }
+            None => None,
+        };
+        self.file = file;
+        // The newline ending this comment moves on to the directive's line
+        self.line = line - 1;
+    }
+
+    fn skip_line_comment(&mut self) {
+        while self.peek() != b'\n' && !self.is_at_end() {
+            self.current += 1
+        }
+    }
 }
 
 fn is_alpha(byte: u8) -> bool {
@@ -201,11 +344,14 @@ pub enum TokenKind {
     // Single-character tokens.
     LeftParen,
     RightParen,
+    LeftBracket,
+    RightBracket,
     RightBrace,
     Comma,
     Minus,
     Plus,
     Slash,
+    FloorSlash,
     Star,
     // One or two character tokens.
     Bang,
@@ -230,12 +376,21 @@ pub enum TokenKind {
     Or,
     Var,
     Print,
+    DebugPrint,
+    PrettyPrint,
     Return,
     True,
     While,
+    Each,
+    In,
+    Repeat,
+    Times,
+    Then,
     Step,
     ParameterAnd,
     Recipe,
+    AboutHeader,
+    AboutEntry,
     IngredientsHeader,
     UtensilsHeader,
     StepsHeader,
@@ -250,10 +405,18 @@ pub struct Token<'src> {
     pub kind: TokenKind,
     pub lexeme: &'src str,
     pub line: usize,
+    pub column: usize,
+    pub file: Option<&'src str>,
 }
 
 impl<'src> Token<'src> {
-    pub fn new(lexeme: &'src str, line: usize, kind: TokenKind) -> Self {
-        Self { kind, lexeme, line }
+    pub fn new(lexeme: &'src str, line: usize, column: usize, kind: TokenKind) -> Self {
+        Self {
+            kind,
+            lexeme,
+            line,
+            column,
+            file: None,
+        }
     }
 }
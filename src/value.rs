@@ -2,7 +2,10 @@ use crate::common::print_function;
 use crate::error::{ChefError, InterpretResult};
 use crate::native_functions::NativeFunction;
 use std::fmt::{Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::mem::discriminant;
 use std::ops::{AddAssign, DivAssign, MulAssign, SubAssign};
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Function {
@@ -16,9 +19,33 @@ pub enum Value {
     Nil,
     Number(f64),
     Boolean(bool),
-    String(String),
+    // Shared, so reading a string ingredient doesn't copy its text
+    String(Rc<str>),
+    List(Vec<Value>),
     Function(Function),
     NativeFunction(NativeFunction),
+    // Placeholder for an ingredient declared without a value, which errors when read
+    Unset(String),
+}
+
+// NaN is the one value not equal to itself, so it can never be found as a key
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        discriminant(self).hash(state);
+        match self {
+            // 0 and -0 are equal, so they must share a bit pattern
+            Self::Number(number) if *number == 0.0 => 0.0f64.to_bits().hash(state),
+            Self::Number(number) => number.to_bits().hash(state),
+            Self::Boolean(boolean) => boolean.hash(state),
+            Self::String(string) => string.hash(state),
+            Self::List(elements) => elements.hash(state),
+            // Nil has no contents, and the rest are rejected by `as_key`, so
+            // equal values still hash equally on the variant alone
+            Self::Nil | Self::Function(_) | Self::NativeFunction(_) | Self::Unset(_) => {}
+        }
+    }
 }
 
 impl Display for Value {
@@ -28,13 +55,80 @@ impl Display for Value {
             Value::Number(number) => write!(f, "{number}"),
             Value::Boolean(boolean) => write!(f, "{boolean}"),
             Value::String(string) => write!(f, "{string}"),
-            Value::Function(function) => write!(f, "{}", print_function(&function.name)),
+            Value::List(elements) => {
+                write!(f, "[")?;
+                for (index, element) in elements.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    // Quoted so `["a, b"]` can't be mistaken for `["a", "b"]`
+                    match element {
+                        Value::String(string) => write!(f, "\"{string}\"")?,
+                        element => write!(f, "{element}")?,
+                    }
+                }
+                write!(f, "]")
+            }
+            Value::Function(function) => {
+                write!(f, "{}", print_function(&function.name, function.arity))
+            }
             Value::NativeFunction(_) => write!(f, "<native fn>"),
+            Value::Unset(name) => write!(f, "<unset {name}>"),
         }
     }
 }
 
 impl Value {
+    pub fn as_number(&self) -> InterpretResult<f64> {
+        match self {
+            Self::Number(number) => Ok(*number),
+            _ => Err(ChefError::ExpectedNumber),
+        }
+    }
+
+    /// The name of this value's type, as used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::Number(_) => "number",
+            Self::Boolean(_) => "boolean",
+            Self::String(_) => "string",
+            Self::List(_) => "list",
+            Self::Function(_) | Self::NativeFunction(_) => "utensil",
+            Self::Unset(_) => "unset ingredient",
+        }
+    }
+
+    pub fn as_string(&self) -> InterpretResult<&str> {
+        match self {
+            Self::String(string) => Ok(string),
+            _ => Err(ChefError::ExpectedString),
+        }
+    }
+
+    /// The value itself when it can be hashed as a map key.
+    pub fn as_key(&self) -> InterpretResult<&Self> {
+        match self {
+            Self::Nil | Self::Number(_) | Self::Boolean(_) | Self::String(_) => Ok(self),
+            Self::List(elements) => {
+                for element in elements {
+                    element.as_key()?;
+                }
+                Ok(self)
+            }
+            Self::Function(_) | Self::NativeFunction(_) | Self::Unset(_) => {
+                Err(ChefError::UnhashableKey)
+            }
+        }
+    }
+
+    pub fn as_list(&self) -> InterpretResult<&[Value]> {
+        match self {
+            Self::List(elements) => Ok(elements),
+            _ => Err(ChefError::ValueListOperation),
+        }
+    }
+
     pub fn negate(&mut self) -> InterpretResult<()> {
         match self {
             Self::Number(number) => *number = -*number,
@@ -75,29 +169,101 @@ impl Value {
         Ok(())
     }
 
-    pub fn falsey(&self) -> bool {
+    pub fn floor_div_assign(&mut self, rhs: Self) -> InterpretResult<()> {
+        match (self, rhs) {
+            (Self::Number(a), Self::Number(b)) => *a = (*a / b).floor(),
+            _ => return Err(ChefError::ValueNumberOnlyOperation),
+        };
+        Ok(())
+    }
+
+    pub fn index(&self, index: Self) -> InterpretResult<Self> {
+        let Self::List(elements) = self else {
+            return Err(ChefError::ValueListOperation);
+        };
+        let Self::Number(index) = index else {
+            return Err(ChefError::ListIndex);
+        };
+        if index.fract() != 0.0 || index < 0.0 {
+            return Err(ChefError::ListIndex);
+        }
+        elements
+            .get(index as usize)
+            .cloned()
+            .ok_or(ChefError::OutOfBounds)
+    }
+
+    pub fn length(&self) -> InterpretResult<usize> {
+        match self {
+            Self::List(elements) => Ok(elements.len()),
+            _ => Err(ChefError::ValueListOperation),
+        }
+    }
+
+    /// The value as `garnish` prints it, with each list element on its own
+    /// line, indented two spaces per level below `indent`.
+    pub fn pretty(&self, indent: usize) -> String {
+        let Self::List(elements) = self else {
+            return self.to_string();
+        };
+        if elements.is_empty() {
+            return "[]".into();
+        }
+        let padding = "  ".repeat(indent + 1);
+        let elements: Vec<_> = elements
+            .iter()
+            .map(|element| match element {
+                Self::String(string) => format!("{padding}\"{string}\""),
+                element => format!("{padding}{}", element.pretty(indent + 1)),
+            })
+            .collect();
+        format!("[\n{}\n{}]", elements.join(",\n"), "  ".repeat(indent))
+    }
+
+    /// Only `false` and `nil` are falsey, everything else is truthy.
+    pub fn truthy(&self) -> bool {
         match self {
-            Self::Boolean(boolean) => !boolean,
-            Self::Nil => true,
-            _ => false,
+            Self::Boolean(boolean) => *boolean,
+            Self::Nil => false,
+            _ => true,
         }
     }
 
+    pub fn falsey(&self) -> bool {
+        !self.truthy()
+    }
+
     pub fn is_equal(&self, rhs: Self) -> bool {
         rhs.eq(self)
     }
 
+    /// Whether `needle` is a substring of this string or an element of
+    /// this list, for the `in` operator.
+    pub fn holds(&self, needle: &Value) -> InterpretResult<bool> {
+        match (self, needle) {
+            (Self::String(haystack), Self::String(needle)) => Ok(haystack.contains(&**needle)),
+            (Self::String(_), needle) => {
+                Err(ChefError::ValueInOperation(needle.type_name(), "string"))
+            }
+            (Self::List(elements), needle) => Ok(elements.contains(needle)),
+            (haystack, needle) => Err(ChefError::ValueInOperation(
+                needle.type_name(),
+                haystack.type_name(),
+            )),
+        }
+    }
+
     pub fn is_greater(&self, rhs: Self) -> InterpretResult<bool> {
         match (self, rhs) {
             (Self::Number(a), Self::Number(b)) => Ok(*a > b),
-            _ => Err(ChefError::ValueNumberOnlyOperation),
+            (a, b) => Err(ChefError::ValueOrderOperation(a.type_name(), b.type_name())),
         }
     }
 
     pub fn is_less(&self, rhs: Self) -> InterpretResult<bool> {
         match (self, rhs) {
             (Self::Number(a), Self::Number(b)) => Ok(*a < b),
-            _ => Err(ChefError::ValueNumberOnlyOperation),
+            (a, b) => Err(ChefError::ValueOrderOperation(a.type_name(), b.type_name())),
         }
     }
 }
@@ -1,8 +1,8 @@
-use std::mem::transmute;
-
+use crate::ansi::Style;
 use crate::code::{Code, Opcode};
 use crate::common::{CALL_FRAMES_MAX_COUNT, STACK_VALUES_MAX_COUNT};
 use crate::error::{ChefError, InterpretResult};
+use crate::native_functions::{Capability, NativeFunction, NATIVE_FUNCTIONS};
 use crate::value::Value;
 
 #[derive(Debug, Default, Clone)]
@@ -13,53 +13,195 @@ pub struct CallFrame {
     pub continuation_ip: usize,
 }
 
+/// One entry of a runtime error backtrace. The script's frame has an empty
+/// name, as in [`CallFrame`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub name: String,
+    pub line: usize,
+}
+
 pub struct State {
     ip: usize,
     code: Code,
-    frames: [Option<CallFrame>; CALL_FRAMES_MAX_COUNT],
+    // Loaded by `Opcode::Native`, so built once rather than per call
+    natives: Vec<NativeFunction>,
+    frames: Vec<Option<CallFrame>>,
     frame_count: usize,
-    stack: [Option<Value>; STACK_VALUES_MAX_COUNT],
+    stack: Vec<Option<Value>>,
     stack_top: usize,
+    style: Style,
+    trace: bool,
+    strict_arity: bool,
+    round_division: bool,
+    allow_env: bool,
+    sandbox: bool,
+    profile: Option<[u64; Opcode::COUNT as usize]>,
+    max_output_lines: Option<usize>,
+    output_lines: usize,
+    recursion_warning: u8,
+    has_warned_recursion: bool,
 }
 
-const FRAME_ARRAY_REPEAT_VALUE: Option<CallFrame> = None;
-const STACK_ARRAY_REPEAT_VALUE: Option<Value> = None;
 impl State {
     pub fn new(code: Code) -> Self {
         Self {
             ip: 0,
             code,
-            frames: [FRAME_ARRAY_REPEAT_VALUE; CALL_FRAMES_MAX_COUNT],
+            natives: NATIVE_FUNCTIONS.iter().map(|(_, native)| *native).collect(),
+            frames: vec![None; CALL_FRAMES_MAX_COUNT],
             frame_count: 0,
-            stack: [STACK_ARRAY_REPEAT_VALUE; STACK_VALUES_MAX_COUNT],
+            stack: vec![None; STACK_VALUES_MAX_COUNT],
             stack_top: 0,
+            style: Style::default(),
+            trace: cfg!(feature = "debug_trace"),
+            strict_arity: false,
+            round_division: false,
+            allow_env: false,
+            sandbox: false,
+            profile: None,
+            max_output_lines: None,
+            output_lines: 0,
+            recursion_warning: 80,
+            has_warned_recursion: false,
         }
     }
 
+    pub fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: bool) -> Self {
+        self.trace |= trace;
+        self
+    }
+
+    /// Make natives reject calls with the wrong number of arguments, rather
+    /// than ignoring extra ones.
+    pub fn with_strict_arity(mut self, strict_arity: bool) -> Self {
+        self.strict_arity = strict_arity;
+        self
+    }
+
+    /// Floor the result of every `split`, as `floor_split` always does.
+    pub fn with_round_division(mut self, round_division: bool) -> Self {
+        self.round_division = round_division;
+        self
+    }
+
+    /// Let the `env` native read environment variables, which it otherwise
+    /// refuses to.
+    pub fn with_allow_env(mut self, allow_env: bool) -> Self {
+        self.allow_env = allow_env;
+        self
+    }
+
+    /// Refuse every native that reaches the host, such as `time` and
+    /// `read_line`, even when [`State::with_allow_env`] is set.
+    pub fn with_sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Allow `stack_size` values on the stack before a stack overflow. Call
+    /// before pushing the script's frame.
+    pub fn with_stack_size(mut self, stack_size: usize) -> Self {
+        self.stack = vec![None; stack_size];
+        self
+    }
+
+    /// Allow `max_frames` nested calls, counting the script itself, before a
+    /// stack overflow. Call before pushing the script's frame.
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        self.frames = vec![None; max_frames];
+        self
+    }
+
+    /// Warn once when calls nest deeper than `percent` of the frame limit,
+    /// 80 by default. 0 turns the warning off.
+    pub fn with_recursion_warning(mut self, percent: u8) -> Self {
+        self.recursion_warning = percent;
+        self
+    }
+
+    /// Error rather than run more than `max_output_lines` `taste` steps.
+    pub fn with_max_output_lines(mut self, max_output_lines: usize) -> Self {
+        self.max_output_lines = Some(max_output_lines);
+        self
+    }
+
+    /// Count how many times each opcode runs, for [`State::profile`].
+    pub fn with_profile(mut self, profile: bool) -> Self {
+        self.profile = profile.then_some([0; Opcode::COUNT as usize]);
+        self
+    }
+
+    /// Opcodes that ran while profiling, most frequent first.
+    pub fn profile(&self) -> Vec<(Opcode, u64)> {
+        let Some(counts) = &self.profile else {
+            return Vec::new();
+        };
+        let mut profile: Vec<(Opcode, u64)> = counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(byte, count)| Some((Opcode::from_byte(byte as u8)?, *count)))
+            .collect();
+        profile.sort_by(|(_, a), (_, b)| b.cmp(a));
+        profile
+    }
+
     fn reset(&mut self) {
         self.stack_top = 0;
         self.frame_count = 0;
     }
 
-    fn current_frame_mut(&mut self) -> &mut CallFrame {
-        self.frames[self.frame_count - 1].as_mut().unwrap()
+    fn current_frame_mut(&mut self) -> InterpretResult<&mut CallFrame> {
+        self.frame_count
+            .checked_sub(1)
+            .and_then(|index| self.frames.get_mut(index)?.as_mut())
+            .ok_or(ChefError::StackUnderflow)
     }
 
     pub fn stack_error(&mut self) {
-        self.current_frame_mut().line = self.code.lines[self.ip];
-        for frame_count in (0..self.frame_count).rev() {
-            let frame = self.frames[frame_count].as_ref().unwrap();
-            let line = frame.line;
+        for frame in self.backtrace() {
+            let line = self.style.dim(&format!("[line {}]", frame.line));
             match frame.name.is_empty() {
-                true => eprintln!("[line {line}] in script"),
-                false => eprintln!("[line {line}] in {}", frame.name),
+                true => eprintln!("{line} in script"),
+                false => eprintln!("{line} in {}", frame.name),
             }
         }
         self.reset();
     }
 
+    /// The frames active when `run` returned an error, innermost first.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        self.frames[..self.frame_count]
+            .iter()
+            .rev()
+            .flatten()
+            .enumerate()
+            .map(|(index, frame)| Frame {
+                name: frame.name.clone(),
+                // Outer frames saved their line when they made the call
+                line: match index {
+                    0 => self.current_line(),
+                    _ => frame.line,
+                },
+            })
+            .collect()
+    }
+
+    /// The line of the instruction being run. `ip` has already moved past
+    /// it, and the next instruction can be on a later line.
+    fn current_line(&self) -> usize {
+        let offset = self.ip.saturating_sub(1);
+        self.code.lines.get(offset).copied().unwrap_or_default()
+    }
+
     pub fn push_frame(&mut self, frame: CallFrame) -> InterpretResult<()> {
-        if self.frame_count == CALL_FRAMES_MAX_COUNT {
+        if self.frame_count == self.frames.len() {
             return Err(ChefError::StackOverflow);
         }
         self.frames[self.frame_count] = Some(frame);
@@ -67,13 +209,34 @@ impl State {
         Ok(())
     }
 
-    fn pop_frame(&mut self) -> CallFrame {
+    /// Warn once when a call made on `line` takes the frames past the
+    /// recursion warning's share of the limit.
+    fn check_recursion_depth(&mut self, line: usize) {
+        let limit = self.frames.len();
+        let percent = self.recursion_warning as usize;
+        if percent == 0 || self.has_warned_recursion || self.frame_count * 100 <= limit * percent {
+            return;
+        }
+        self.has_warned_recursion = true;
+        let line = self.style.dim(&format!("[line {line}]"));
+        let warning = self.style.warning("Warning");
+        eprintln!(
+            "{line} {warning}: Calls are {} deep, past {percent}% of the limit of {limit}.",
+            self.frame_count
+        );
+    }
+
+    fn pop_frame(&mut self) -> InterpretResult<CallFrame> {
+        let index = self.frame_count.checked_sub(1);
+        let frame = index
+            .and_then(|index| self.frames.get_mut(index)?.take())
+            .ok_or(ChefError::StackUnderflow)?;
         self.frame_count -= 1;
-        self.frames[self.frame_count].take().unwrap()
+        Ok(frame)
     }
 
     pub fn push(&mut self, value: Value) -> InterpretResult<()> {
-        if self.stack_top == STACK_VALUES_MAX_COUNT {
+        if self.stack_top == self.stack.len() {
             return Err(ChefError::StackOverflow);
         }
         self.stack[self.stack_top] = Some(value);
@@ -81,31 +244,57 @@ impl State {
         Ok(())
     }
 
-    fn pop(&mut self) -> Value {
+    fn pop(&mut self) -> InterpretResult<Value> {
+        let index = self.stack_top.checked_sub(1);
+        let value = index
+            .and_then(|index| self.stack[index].take())
+            .ok_or(ChefError::StackUnderflow)?;
         self.stack_top -= 1;
-        self.stack[self.stack_top].take().unwrap()
+        Ok(value)
+    }
+
+    fn try_peek(&self, depth: usize) -> InterpretResult<&Value> {
+        self.stack_top
+            .checked_sub(depth + 1)
+            .and_then(|index| self.stack[index].as_ref())
+            .ok_or(ChefError::StackUnderflow)
     }
 
-    fn peek(&self, depth: usize) -> &Value {
-        self.stack[self.stack_top - 1 - depth].as_ref().unwrap()
+    /// Take the top `count` values off the stack, bottom first.
+    fn take_values(&mut self, count: usize) -> InterpretResult<Vec<Value>> {
+        let start = self
+            .stack_top
+            .checked_sub(count)
+            .ok_or(ChefError::StackUnderflow)?;
+        let values = self.stack[start..self.stack_top]
+            .iter_mut()
+            .map(|value| value.take().ok_or(ChefError::StackUnderflow))
+            .collect();
+        self.stack_top = start;
+        values
     }
 
     pub fn run(&mut self) -> InterpretResult<()> {
         loop {
-            let byte = self.read_byte();
-            #[cfg(feature = "debug_trace")]
-            self.code.disassemble_instruction(self.ip - 1);
-            let opcode: Opcode = unsafe { transmute(byte) };
+            let byte = self.read_byte()?;
+            let opcode = Opcode::from_byte(byte).ok_or(ChefError::InvalidBytecode)?;
+            if self.trace {
+                let (instruction, _) = self.code.disassemble_instruction(self.ip - 1);
+                eprintln!("{instruction}");
+            }
+            if let Some(counts) = &mut self.profile {
+                counts[opcode as usize] += 1;
+            }
             match opcode {
                 Opcode::Return => {
-                    let result = self.pop();
-                    let frame = self.pop_frame();
+                    let result = self.pop()?;
+                    let frame = self.pop_frame()?;
                     if self.frame_count == 0 {
                         return Ok(());
                     }
                     self.stack_top = frame.stack_index;
                     self.ip = frame.continuation_ip;
-                    self.pop();
+                    self.pop()?;
                     self.push(result)?;
                 }
                 Opcode::Constant => self.op_constant()?,
@@ -114,6 +303,7 @@ impl State {
                 Opcode::Subtract => self.op_subtract()?,
                 Opcode::Multiply => self.op_multiply()?,
                 Opcode::Divide => self.op_divide()?,
+                Opcode::FloorDivide => self.op_floor_divide()?,
                 Opcode::Nil => self.op_nil()?,
                 Opcode::True => self.op_true()?,
                 Opcode::False => self.op_false()?,
@@ -121,38 +311,48 @@ impl State {
                 Opcode::Equal => self.op_equal()?,
                 Opcode::Greater => self.op_greater()?,
                 Opcode::Less => self.op_less()?,
-                Opcode::Print => self.op_print(),
-                Opcode::Pop => drop(self.pop()),
+                Opcode::In => self.op_in()?,
+                Opcode::Print => self.op_print(false)?,
+                Opcode::PrettyPrint => self.op_print(true)?,
+                Opcode::DebugPrint => self.op_debug_print()?,
+                Opcode::Pop => drop(self.pop()?),
                 Opcode::GetLocal => self.op_get_local()?,
-                Opcode::SetLocal => self.op_set_local(),
-                Opcode::JumpIfFalse => self.op_jump_if_false(),
-                Opcode::Jump => self.op_jump(),
-                Opcode::Loop => self.op_loop(),
+                Opcode::SetLocal => self.op_set_local()?,
+                Opcode::JumpIfFalse => self.op_jump_if_false()?,
+                Opcode::JumpIfTrue => self.op_jump_if_true()?,
+                Opcode::Jump => self.op_jump()?,
+                Opcode::Loop => self.op_loop()?,
                 Opcode::Call => self.op_call()?,
+                Opcode::List => self.op_list()?,
+                Opcode::PopN => self.op_pop_n()?,
+                Opcode::Native => self.op_native()?,
+                Opcode::Index => self.op_index()?,
+                Opcode::Length => self.op_length()?,
+                Opcode::Dup => self.op_dup()?,
+                Opcode::Nop => {}
             };
         }
     }
 
     fn op_constant(&mut self) -> InterpretResult<()> {
-        let constant_index = self.read_byte();
+        let constant_index = self.read_byte()?;
         let value = self.read_constant(constant_index)?;
         self.push(value)?;
         Ok(())
     }
 
     fn op_negate(&mut self) -> InterpretResult<()> {
-        let mut constant = self.pop();
+        let mut constant = self.pop()?;
         constant.negate()?;
         self.push(constant)?;
         Ok(())
     }
 
     fn op_add(&mut self) -> InterpretResult<()> {
-        let (b, mut a) = (self.pop(), self.pop());
-        match (a.clone(), &b) {
-            (Value::String(mut a), Value::String(b)) => {
-                a.push_str(b);
-                self.push(Value::String(a.to_string()))?;
+        let (b, mut a) = (self.pop()?, self.pop()?);
+        match (&a, &b) {
+            (Value::String(a), Value::String(b)) => {
+                self.push(Value::String(format!("{a}{b}").into()))?;
             }
             _ => {
                 a.add_assign(b)?;
@@ -163,22 +363,32 @@ impl State {
     }
 
     fn op_subtract(&mut self) -> InterpretResult<()> {
-        let (b, mut a) = (self.pop(), self.pop());
+        let (b, mut a) = (self.pop()?, self.pop()?);
         a.sub_assign(b)?;
         self.push(a)?;
         Ok(())
     }
 
     fn op_multiply(&mut self) -> InterpretResult<()> {
-        let (b, mut a) = (self.pop(), self.pop());
+        let (b, mut a) = (self.pop()?, self.pop()?);
         a.mul_assign(b)?;
         self.push(a)?;
         Ok(())
     }
 
     fn op_divide(&mut self) -> InterpretResult<()> {
-        let (b, mut a) = (self.pop(), self.pop());
-        a.div_assign(b)?;
+        let (b, mut a) = (self.pop()?, self.pop()?);
+        match self.round_division {
+            true => a.floor_div_assign(b)?,
+            false => a.div_assign(b)?,
+        }
+        self.push(a)?;
+        Ok(())
+    }
+
+    fn op_floor_divide(&mut self) -> InterpretResult<()> {
+        let (b, mut a) = (self.pop()?, self.pop()?);
+        a.floor_div_assign(b)?;
         self.push(a)?;
         Ok(())
     }
@@ -199,90 +409,196 @@ impl State {
     }
 
     fn op_not(&mut self) -> InterpretResult<()> {
-        let constant = self.pop();
-        let result = constant.falsey();
-        self.push(Value::Boolean(result))?;
+        let constant = self.pop()?;
+        self.push(Value::Boolean(!constant.truthy()))?;
         Ok(())
     }
 
     fn op_equal(&mut self) -> InterpretResult<()> {
-        let (b, a) = (self.pop(), self.pop());
+        let (b, a) = (self.pop()?, self.pop()?);
         let result = a.is_equal(b);
         self.push(Value::Boolean(result))?;
         Ok(())
     }
 
     fn op_greater(&mut self) -> InterpretResult<()> {
-        let (b, a) = (self.pop(), self.pop());
+        let (b, a) = (self.pop()?, self.pop()?);
         let result = a.is_greater(b)?;
         self.push(Value::Boolean(result))?;
         Ok(())
     }
 
+    fn op_in(&mut self) -> InterpretResult<()> {
+        let (haystack, needle) = (self.pop()?, self.pop()?);
+        let result = haystack.holds(&needle)?;
+        self.push(Value::Boolean(result))?;
+        Ok(())
+    }
+
     fn op_less(&mut self) -> InterpretResult<()> {
-        let (b, a) = (self.pop(), self.pop());
+        let (b, a) = (self.pop()?, self.pop()?);
         let result = a.is_less(b)?;
         self.push(Value::Boolean(result))?;
         Ok(())
     }
 
-    fn op_print(&mut self) {
-        let constant = self.pop();
-        println!("{constant}");
+    fn op_print(&mut self, pretty: bool) -> InterpretResult<()> {
+        if let Some(max_output_lines) = self.max_output_lines {
+            if self.output_lines == max_output_lines {
+                return Err(ChefError::OutputLimitExceeded(max_output_lines));
+            }
+            self.output_lines += 1;
+        }
+        let constant = self.pop()?;
+        match pretty {
+            true => println!("{}", constant.pretty(0)),
+            false => println!("{constant}"),
+        }
+        Ok(())
+    }
+
+    fn op_debug_print(&mut self) -> InterpretResult<()> {
+        let constant = self.pop()?;
+        eprintln!("{constant}");
+        Ok(())
+    }
+
+    fn op_loop(&mut self) -> InterpretResult<()> {
+        let offset = self.read_u16()?;
+        self.jump_to(self.ip.checked_sub(offset))
     }
 
-    fn op_loop(&mut self) {
-        let offset = self.read_u16();
-        self.ip -= offset;
+    fn op_jump(&mut self) -> InterpretResult<()> {
+        let offset = self.read_u16()?;
+        self.jump_to(self.ip.checked_add(offset))
     }
 
-    fn op_jump(&mut self) {
-        let offset = self.read_u16();
-        self.ip += offset
+    fn op_jump_if_false(&mut self) -> InterpretResult<()> {
+        let offset = self.read_u16()?;
+        let value = self.try_peek(0)?;
+        if !value.truthy() {
+            self.jump_to(self.ip.checked_add(offset))?;
+        }
+        Ok(())
     }
 
-    fn op_jump_if_false(&mut self) {
-        let offset = self.read_u16();
-        let value = self.peek(0);
-        if value.falsey() {
-            self.ip += offset;
+    fn op_jump_if_true(&mut self) -> InterpretResult<()> {
+        let offset = self.read_u16()?;
+        let value = self.try_peek(0)?;
+        if value.truthy() {
+            self.jump_to(self.ip.checked_add(offset))?;
+        }
+        Ok(())
+    }
+
+    // Guard against corrupt offsets jumping outside the bytecode
+    fn jump_to(&mut self, target: Option<usize>) -> InterpretResult<()> {
+        match target {
+            Some(target) if target < self.code.bytes.len() => {
+                self.ip = target;
+                Ok(())
+            }
+            _ => Err(ChefError::OutOfBounds),
         }
     }
 
     fn op_get_local(&mut self) -> InterpretResult<()> {
-        let index = self.read_byte();
-        let frame_pops = self.read_byte();
-        let frame = self.frames[self.frame_count - 1 - frame_pops as usize]
-            .as_ref()
-            .unwrap();
-        let stack_index = frame.stack_index + index as usize;
-        let value = self.stack[stack_index].as_ref().unwrap();
-        self.push(value.clone())?;
+        let index = self.read_byte()?;
+        let depth = self.read_byte()?;
+        let stack_index = self.local_frame(depth)?.stack_index + index as usize;
+        let value = self
+            .stack
+            .get(stack_index)
+            .and_then(Option::as_ref)
+            .ok_or(ChefError::StackUnderflow)?;
+        if let Value::Unset(name) = value {
+            return Err(ChefError::UndefinedVariable(name.clone()));
+        }
+        // Scalars copy and strings share their text, so this only allocates for lists
+        self.push(value.clone())
+    }
+
+    /// The frame owning a local resolved `depth` compilers out. Utensils
+    /// can't nest, so anything outside the current frame is the script's,
+    /// however deep the call stack is.
+    fn local_frame(&self, depth: u8) -> InterpretResult<&CallFrame> {
+        let frame_index = match depth {
+            0 => self.frame_count.checked_sub(1),
+            _ => Some(0),
+        };
+        frame_index
+            .and_then(|index| self.frames.get(index)?.as_ref())
+            .ok_or(ChefError::StackUnderflow)
+    }
+
+    fn op_set_local(&mut self) -> InterpretResult<()> {
+        let index = self.read_byte()?;
+        let depth = self.read_byte()?;
+        let stack_index = self.local_frame(depth)?.stack_index + index as usize;
+        if stack_index >= self.stack_top {
+            return Err(ChefError::StackUnderflow);
+        }
+        self.stack[stack_index] = Some(self.try_peek(0)?.clone());
+        Ok(())
+    }
+
+    fn op_native(&mut self) -> InterpretResult<()> {
+        let index = self.read_byte()? as usize;
+        let native = *self.natives.get(index).ok_or(ChefError::OutOfBounds)?;
+        self.push(Value::NativeFunction(native))
+    }
+
+    fn op_dup(&mut self) -> InterpretResult<()> {
+        let value = self.try_peek(0)?.clone();
+        self.push(value)
+    }
+
+    fn op_pop_n(&mut self) -> InterpretResult<()> {
+        let count = self.read_byte()? as usize;
+        self.take_values(count)?;
+        Ok(())
+    }
+
+    fn op_list(&mut self) -> InterpretResult<()> {
+        let element_count = self.read_byte()? as usize;
+        let elements = self.take_values(element_count)?;
+        self.push(Value::List(elements))?;
+        Ok(())
+    }
+
+    fn op_index(&mut self) -> InterpretResult<()> {
+        let (index, list) = (self.pop()?, self.pop()?);
+        let element = list.index(index)?;
+        self.push(element)?;
         Ok(())
     }
 
-    fn op_set_local(&mut self) {
-        let index = self.read_byte();
-        let frame_pops = self.read_byte();
-        let frame = self.frames[self.frame_count - 1 - frame_pops as usize]
-            .as_ref()
-            .unwrap();
-        let stack_index = frame.stack_index + index as usize;
-        let replacement_value = self.peek(0);
-        self.stack[stack_index] = Some(replacement_value.clone());
+    fn op_length(&mut self) -> InterpretResult<()> {
+        let list = self.pop()?;
+        let length = list.length()?;
+        self.push(Value::Number(length as f64))?;
+        Ok(())
     }
 
     fn op_call(&mut self) -> InterpretResult<()> {
-        let argument_count = self.read_byte();
+        let argument_count = self.read_byte()?;
         self.call(argument_count)
     }
 
     pub fn call(&mut self, argument_count: u8) -> InterpretResult<()> {
-        let callee = self.peek(argument_count as usize).clone();
+        let callee = self.try_peek(argument_count as usize)?.clone();
         match callee {
-            Value::NativeFunction(function) => {
-                let result = function(argument_count, self.stack_top - argument_count as usize);
-                self.stack_top -= 1;
+            Value::NativeFunction(native) => {
+                if self.strict_arity && native.arity != argument_count {
+                    return Err(ChefError::FunctionArity(native.arity, argument_count));
+                }
+                if let Some(capability) = native.capability {
+                    self.check_capability(capability)?;
+                }
+                let arguments = self.take_values(argument_count as usize)?;
+                // Discard the callee along with its arguments
+                self.pop()?;
+                let result = (native.function)(&arguments)?;
                 self.push(result)?;
                 Ok(())
             }
@@ -290,13 +606,16 @@ impl State {
                 if function.arity != argument_count {
                     return Err(ChefError::FunctionArity(function.arity, argument_count));
                 }
-                self.current_frame_mut().line = self.code.lines[self.ip];
+                let line = self.current_line();
+                self.current_frame_mut()?.line = line;
                 self.push_frame(CallFrame {
                     name: function.name.clone(),
                     line: 0,
                     stack_index: self.stack_top - argument_count as usize,
                     continuation_ip: self.ip,
                 })?;
+                // Only calls count, so the script's own frame never warns
+                self.check_recursion_depth(line);
                 self.ip = function.ip_start;
                 Ok(())
             }
@@ -304,6 +623,20 @@ impl State {
         }
     }
 
+    fn check_capability(&self, capability: Capability) -> InterpretResult<()> {
+        match capability {
+            _ if self.sandbox => Err(ChefError::CapabilityRefused(
+                capability.description(),
+                "in the sandbox",
+            )),
+            Capability::Env if !self.allow_env => Err(ChefError::CapabilityRefused(
+                capability.description(),
+                "without --allow-env",
+            )),
+            _ => Ok(()),
+        }
+    }
+
     fn read_constant(&self, index: u8) -> InterpretResult<Value> {
         let value = self
             .code
@@ -313,16 +646,20 @@ impl State {
         Ok(value.clone())
     }
 
-    fn read_u16(&mut self) -> usize {
-        self.ip += 2;
-        let byte_1 = self.code.bytes[self.ip - 2];
-        let byte_2 = self.code.bytes[self.ip - 1];
-        u16::from_le_bytes([byte_1, byte_2]) as usize
+    fn read_u16(&mut self) -> InterpretResult<usize> {
+        let byte_1 = self.read_byte()?;
+        let byte_2 = self.read_byte()?;
+        Ok(u16::from_le_bytes([byte_1, byte_2]) as usize)
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let byte = self.code.bytes[self.ip];
+    // Code can end partway through an instruction if it was built by hand
+    fn read_byte(&mut self) -> InterpretResult<u8> {
+        let byte = *self
+            .code
+            .bytes
+            .get(self.ip)
+            .ok_or(ChefError::InvalidBytecode)?;
         self.ip += 1;
-        byte
+        Ok(byte)
     }
 }
@@ -0,0 +1,712 @@
+mod common;
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+
+use common::command;
+
+fn suite_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/suite");
+    path.push(name);
+    path
+}
+
+fn fixture_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/fixtures");
+    path.push(name);
+    path
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).expect("Invalid UTF-8")
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).expect("Invalid UTF-8")
+}
+
+#[test]
+fn color_always_paints_compile_errors() {
+    let output = command()
+        .arg("--color=always")
+        .arg(suite_path("unexpected_character.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        stderr(&output),
+        "\x1b[2m[line 5]\x1b[0m \x1b[1;31mError\x1b[0m in Steps: Unexpected character.\n\x1b[1;31merror[E0001]\x1b[0m: Could not compile.\n"
+    );
+}
+
+#[test]
+fn color_always_paints_runtime_errors() {
+    let output = command()
+        .arg("--color=always")
+        .arg(suite_path("call/num.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        stderr(&output),
+        "\x1b[1;31merror[E0006]\x1b[0m: Can only call functions.\n\x1b[2m[line 5]\x1b[0m in script\n"
+    );
+}
+
+#[test]
+fn no_color_overrides_color_always() {
+    let output = command()
+        .arg("--color=always")
+        .arg("--no-color")
+        .arg(suite_path("unexpected_character.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert!(!stderr(&output).contains('\x1b'));
+}
+
+#[test]
+fn unknown_flag_prints_usage() {
+    let output = command()
+        .arg("--colour")
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(64));
+    assert!(stderr(&output).starts_with("Usage: chef"));
+}
+
+#[test]
+fn trace_prints_operations_to_stderr() {
+    let output = command()
+        .arg("--trace")
+        .arg(suite_path("nil/literal.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "nil\n");
+    // Skip the offsets, which shift as natives are added
+    let trace: Vec<String> = stderr(&output)
+        .lines()
+        .map(|line| line[4..].to_owned())
+        .collect();
+    assert_eq!(
+        trace[trace.len() - 4..],
+        [
+            "         4  Nil",
+            "         |  Print",
+            "         5  Nil",
+            "         |  Return",
+        ]
+    );
+}
+
+#[test]
+fn auto_number_accepts_repeated_step_numbers() {
+    let output = command()
+        .arg("--auto-number")
+        .arg(suite_path("structure/repeated_step_numbers.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "first\nsecond\n");
+}
+
+#[test]
+fn runtime_error_trace_names_utensil_frames() {
+    let output = command()
+        .arg(suite_path("function/runtime_error_in_utensil.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        stderr(&output),
+        "error[E0011]: Operands must be two numbers or two strings.\n[line 5] in bake\n[line 10] in script\n"
+    );
+}
+
+#[test]
+fn include_cycle_is_a_compile_error() {
+    let output = command()
+        .arg(fixture_path("include/cycle.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("cycle_b.recipe, line 2] Error at 'include': Include cycle through"));
+    assert!(stderr.contains("cycle_a.recipe'.\nerror[E0001]: Could not compile.\n"));
+}
+
+#[test]
+fn errors_in_included_recipes_report_their_file() {
+    let output = command()
+        .arg(fixture_path("include/broken.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(65));
+    assert!(stderr(&output)
+        .contains("broken.recipe, line 5] Error in Utensils at 'flour': Undefined variable.\n"));
+}
+
+#[test]
+fn text_outside_included_sections_is_a_compile_error() {
+    let output = command()
+        .arg(fixture_path("include/stray_text.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("not_a_recipe.recipe, line 1] Error at 'include': Included recipe"));
+    assert!(stderr.contains("can only contain 'Ingredients' and 'Utensils'.\n"));
+}
+
+#[test]
+fn disassemble_json_lists_opcodes_and_operands() {
+    let output = command()
+        .arg("disassemble")
+        .arg("--json")
+        .arg(suite_path("each/sum.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    let json = stdout(&output);
+    assert!(json.starts_with("[\n") && json.ends_with("\n]\n"));
+    assert!(json.contains(
+        r#"{"offset": 0, "line": 1, "opcode": "Native", "operands": {"native": "time"}}"#
+    ));
+    assert!(json.contains(r#""opcode": "Constant", "operands": {"constant": "1"}"#));
+    assert!(json.contains(r#""opcode": "GetLocal", "operands": {"stack_index": "#));
+    assert!(json.contains(r#""opcode": "Loop", "operands": {"offset": "#));
+    assert!(json.contains(r#""opcode": "Return", "operands": {}"#));
+}
+
+#[test]
+fn disassemble_requires_a_path() {
+    let output = command()
+        .arg("disassemble")
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn deny_warnings_fails_without_running() {
+    let output = command()
+        .arg("--deny-warnings")
+        .arg(suite_path("function/shadow_native_warning.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "[line 5] Warning in Utensils at 'trim': 'trim' shadows the native utensil of the same name.\nerror[E0001]: Could not compile.\n"
+    );
+}
+
+#[test]
+fn deny_warnings_allows_a_recipe_without_warnings() {
+    let output = command()
+        .args(["--deny-warnings", "--warn-no-effect"])
+        .arg(suite_path("repeat/taste_in_body.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "stir\n".repeat(5));
+}
+
+#[test]
+fn warn_no_effect_flags_steps_without_side_effects() {
+    let output = command()
+        .arg("--warn-no-effect")
+        .arg(suite_path("expressions/no_effect.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stderr(&output),
+        "[line 7] Warning in Steps at 'egg': Step has no effect.\n\
+         [line 10] Warning in Steps at 'egg': Step has no effect.\n"
+    );
+}
+
+#[test]
+fn repl_prints_bare_expression_values() {
+    let mut child = command()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Command execution error.");
+    child
+        .stdin
+        .take()
+        .expect("Missing stdin.")
+        .write_all(b"3 add 4\ntaste \"whisked\"\n")
+        .expect("Could not write to stdin.");
+    let output = child.wait_with_output().expect("Command execution error.");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "chef > 7\nchef > whisked\nchef > \n");
+}
+
+#[test]
+fn dump_constants_lists_the_constant_table() {
+    let output = command()
+        .arg("--dump-constants")
+        .arg(suite_path("include/utensil.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("0000  sweet\n"));
+    assert!(stdout.contains("0001  4\n"));
+    assert!(stdout.contains("0002  <fn bake/1>\n"));
+}
+
+#[test]
+fn strict_arity_rejects_extra_native_arguments() {
+    let output = command()
+        .arg("--strict-arity")
+        .arg(suite_path("native/extra_argument.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "error[E0007]: Expected 1 arguments but got 2.\n[line 5] in script\n"
+    );
+}
+
+#[test]
+fn eval_runs_a_recipe_from_the_command_line() {
+    let output = command()
+        .arg("--eval")
+        .arg("Recipe Steps 1. taste 3 add 4 2. end")
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "7\n");
+}
+
+#[test]
+fn eval_with_a_path_is_a_usage_error() {
+    let output = command()
+        .arg("--eval")
+        .arg("Recipe Steps 1. end")
+        .arg(suite_path("empty_file.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn help_lists_subcommands_and_options() {
+    for flag in ["help", "-h", "--help"] {
+        let output = command()
+            .arg(flag)
+            .output()
+            .expect("Command execution error.");
+        assert_eq!(output.status.code(), Some(0));
+        let stdout = stdout(&output);
+        assert!(stdout.starts_with("Usage: chef"));
+        for listed in ["disassemble", "help", "--eval <source>", "--strict-arity"] {
+            assert!(stdout.contains(listed), "'{listed}' missing from help");
+        }
+    }
+}
+
+#[test]
+fn round_division_floors_split_results() {
+    let output = command()
+        .arg("--round-division")
+        .arg(suite_path("operator/floor_split.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n3\n-4\n4\ninf\n");
+}
+
+#[test]
+fn profile_lists_the_most_frequent_opcode_first() {
+    let output = command()
+        .arg("--profile")
+        .arg(fixture_path("loop_sum.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "499500\n");
+    let stderr = stderr(&output);
+    // Each line is an opcode, its count and a bar scaled to the top count
+    let rows: Vec<(&str, u64, &str)> = stderr
+        .lines()
+        .map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            assert_eq!(fields.len(), 3, "{stderr}");
+            (fields[0], fields[1].parse().unwrap(), fields[2])
+        })
+        .collect();
+    assert_eq!(rows[0].0, "GetLocal", "{stderr}");
+    assert_eq!(rows[0].2, "#".repeat(40), "{stderr}");
+    for pair in rows.windows(2) {
+        assert!(pair[0].1 >= pair[1].1, "{stderr}");
+        assert!(pair[0].2.len() >= pair[1].2.len(), "{stderr}");
+    }
+    assert!(rows.iter().all(|(_, _, bar)| bar.chars().all(|c| c == '#')));
+}
+
+#[test]
+fn recursion_warning_threshold_is_configurable() {
+    let output = command()
+        .args(["--recursion-warning", "0"])
+        .arg(suite_path("function/recursion_near_limit.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(stderr(&output), "");
+    let output = command()
+        .args(["--recursion-warning", "50"])
+        .arg(suite_path("function/recursion_near_limit.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(
+        stderr(&output),
+        "[line 12] Warning: Calls are 33 deep, past 50% of the limit of 64.\n"
+    );
+}
+
+#[test]
+fn recipe_without_calls_never_warns_about_recursion() {
+    for args in [
+        vec!["--max-frames", "1"],
+        vec!["--max-frames", "2", "--recursion-warning", "10"],
+    ] {
+        let output = command()
+            .args(&args)
+            .arg(suite_path("nil/literal.chef"))
+            .output()
+            .expect("Command execution error.");
+        assert!(output.status.success(), "{args:?}");
+        assert_eq!(stderr(&output), "", "{args:?}");
+    }
+}
+
+#[test]
+fn max_frames_allows_deeper_recursion() {
+    let output = command()
+        .args(["--max-frames", "200"])
+        .arg(suite_path("limit/deep_recursion.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "100\n");
+}
+
+#[test]
+fn stack_size_limits_the_values_on_the_stack() {
+    let output = command()
+        .args(["--max-frames", "200", "--stack-size", "50"])
+        .arg(suite_path("limit/deep_recursion.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert!(stderr(&output).contains("Stack overflow."));
+}
+
+#[test]
+fn limits_must_be_positive() {
+    for args in [
+        ["--max-frames", "0"],
+        ["--stack-size", "-1"],
+        ["--stack-size", "big"],
+    ] {
+        let output = command()
+            .args(args)
+            .arg(suite_path("limit/deep_recursion.chef"))
+            .output()
+            .expect("Command execution error.");
+        assert_eq!(output.status.code(), Some(64), "{args:?}");
+    }
+}
+
+#[test]
+fn limits_have_an_upper_bound() {
+    for args in [["--stack-size", "1048577"], ["--max-frames", "65537"]] {
+        let output = command()
+            .args(args)
+            .arg(suite_path("limit/deep_recursion.chef"))
+            .output()
+            .expect("Command execution error.");
+        assert_eq!(output.status.code(), Some(64), "{args:?}");
+    }
+    let output = command()
+        .args(["--stack-size", "1048576", "--max-frames", "65536"])
+        .arg(suite_path("limit/deep_recursion.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn max_output_lines_stops_a_runaway_taste() {
+    let output = command()
+        .args(["--max-output-lines", "3"])
+        .arg(fixture_path("endless_taste.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "0\n1\n2\n");
+    assert!(stderr(&output).starts_with("error[E0019]: Output limit of 3 lines exceeded."));
+}
+
+#[test]
+fn debug_taste_writes_to_stderr() {
+    let output = command()
+        .arg(fixture_path("debug_taste.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "served\n");
+    assert_eq!(stderr(&output), "2\n[2, \"done\"]\n");
+}
+
+#[test]
+fn explain_prints_the_explanation_of_an_error_code() {
+    let output = command()
+        .args(["--explain", "E0003"])
+        .output()
+        .expect("Command execution error.");
+    assert!(output.status.success());
+    assert!(stdout(&output).starts_with("The VM ran out of stack or call frames"));
+
+    let output = command()
+        .args(["--explain", "E9999"])
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(64));
+    assert_eq!(stderr(&output), "Unknown error code 'E9999'.\n");
+}
+
+#[test]
+fn about_prints_metadata_instead_of_running() {
+    let output = command()
+        .arg("--about")
+        .arg(suite_path("about/ignored_at_runtime.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "serves: 4\nallergens: egg, milk\n");
+}
+
+#[test]
+fn check_syntax_only_prints_every_diagnostic_as_json() {
+    let output = command()
+        .args(["--check-syntax-only", "--json"])
+        .arg(fixture_path("two_errors.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        stdout(&output),
+        r#"[
+  {"line": 5, "column": 5, "severity": "error", "message": "Expect ')' after grouping expression. (found '2.')", "location": "'2.'", "file": null, "section": "Steps"},
+  {"line": 5, "column": 14, "severity": "error", "message": "Undefined variable.", "location": "'egg'", "file": null, "section": "Steps"}
+]
+"#
+    );
+    assert_eq!(stderr(&output), "");
+
+    let output = command()
+        .args(["--check-syntax-only", "--json"])
+        .arg(fixture_path("loop_sum.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "[]\n");
+}
+
+#[test]
+fn json_is_only_accepted_by_disassemble_and_check_syntax_only() {
+    for args in [vec!["--json"], vec!["--dump-ast", "--json"]] {
+        let output = command()
+            .args(&args)
+            .arg(fixture_path("loop_sum.chef"))
+            .output()
+            .expect("Command execution error.");
+        assert_eq!(output.status.code(), Some(64), "{args:?}");
+    }
+}
+
+#[test]
+fn json_is_accepted_before_or_after_check_syntax_only() {
+    for args in [
+        ["--json", "--check-syntax-only"],
+        ["--check-syntax-only", "--json"],
+    ] {
+        let output = command()
+            .args(args)
+            .arg(fixture_path("loop_sum.chef"))
+            .output()
+            .expect("Command execution error.");
+        assert!(output.status.success(), "{args:?}");
+        assert_eq!(stdout(&output), "[]\n", "{args:?}");
+    }
+}
+
+#[test]
+fn env_reads_variables_only_when_allowed() {
+    let output = command()
+        .arg("--allow-env")
+        .arg(fixture_path("env.chef"))
+        .env("CHEF_TEST_FLOUR", "plain")
+        .env_remove("CHEF_TEST_UNSET")
+        .output()
+        .expect("Command execution error.");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "plain\nnil\n");
+
+    let output = command()
+        .arg(fixture_path("env.chef"))
+        .env("CHEF_TEST_FLOUR", "plain")
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).starts_with(
+        "error[E0021]: Reading environment variables is not allowed without --allow-env.\n[line 4]"
+    ));
+
+    // An unset variable is refused too, rather than showing it doesn't exist
+    let output = command()
+        .arg("--eval")
+        .arg("Recipe Steps 1. taste env with \"CHEF_TEST_UNSET\" 2. end")
+        .env_remove("CHEF_TEST_UNSET")
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).starts_with(
+        "error[E0021]: Reading environment variables is not allowed without --allow-env."
+    ));
+}
+
+#[test]
+fn read_line_reads_stdin_until_it_runs_out() {
+    let mut child = command()
+        .arg(fixture_path("read_line.chef"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Command execution error.");
+    child
+        .stdin
+        .take()
+        .expect("Missing stdin.")
+        .write_all(b"self raising flour\r\n")
+        .expect("Could not write to stdin.");
+    let output = child.wait_with_output().expect("Command execution error.");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "self raising flour\nnil\n");
+}
+
+#[test]
+fn sandbox_refuses_natives_that_reach_the_host() {
+    let output = command()
+        .arg("--sandbox")
+        .arg(fixture_path("read_line.chef"))
+        .stdin(Stdio::null())
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert!(stderr(&output)
+        .starts_with("error[E0021]: Reading input is not allowed in the sandbox.\n[line 4]"));
+
+    let output = command()
+        .args(["--sandbox", "--allow-env"])
+        .arg(fixture_path("env.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert!(stderr(&output)
+        .starts_with("error[E0021]: Reading environment variables is not allowed in the sandbox."));
+
+    let output = command()
+        .args(["--sandbox", "--eval"])
+        .arg("Recipe Steps 1. taste sum with [1, 2] 2. taste time now 3. end")
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "3\n");
+    assert!(stderr(&output)
+        .starts_with("error[E0021]: Reading the clock is not allowed in the sandbox."));
+}
+
+#[test]
+fn sandbox_refuses_includes() {
+    let output = command()
+        .arg(suite_path("include/diamond.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert!(output.status.success());
+
+    let output = command()
+        .arg("--sandbox")
+        .arg(suite_path("include/diamond.chef"))
+        .output()
+        .expect("Command execution error.");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).ends_with(
+        "line 3] Error at 'include': Can't include recipes in the sandbox.\nerror[E0001]: Could not compile.\n"
+    ));
+}
+
+#[test]
+fn dump_ast_prints_the_tree_instead_of_running() {
+    let output = command()
+        .args(["--dump-ast", "--eval"])
+        .arg("Recipe Steps 1. check 1 below 2 1. taste \"yes\" 2. end")
+        .output()
+        .expect("Command execution error.");
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "(recipe\n  (steps\n    (check (below 1 2)\n      (taste \"yes\"))))\n"
+    );
+}
+
+#[test]
+fn repl_tastes_a_top_level_serve_and_stops() {
+    let mut child = command()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Command execution error.");
+    child
+        .stdin
+        .take()
+        .expect("Missing stdin.")
+        .write_all(b"serve 3 add 4\nRecipe Steps 1. taste 1 2. serve 2 3. end\n")
+        .expect("Could not write to stdin.");
+    let output = child.wait_with_output().expect("Command execution error.");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "chef > 7\nchef > 1\n2\nchef > \n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn version_prints_the_crate_version() {
+    for flag in ["version", "--version"] {
+        let output = command()
+            .arg(flag)
+            .output()
+            .expect("Command execution error.");
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(
+            stdout(&output),
+            format!("chef {}\n", env!("CARGO_PKG_VERSION"))
+        );
+    }
+}
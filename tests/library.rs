@@ -0,0 +1,1017 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use chef::{
+    compile, compile_with_options, dump_ast, explain, keywords, optimize, try_run, CallFrame,
+    ChefError, Code, CompileOptions, Diagnostic, Frame, InterpretResult, Opcode, RunError,
+    Severity, State, Value, BYTECODE_VERSION,
+};
+
+#[test]
+fn compile_returns_all_diagnostics() {
+    let source = "Recipe\n\nSteps\n    1. taste (1 add 2\n    2. taste egg\n    3. end\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(
+        diagnostics,
+        vec![
+            Diagnostic {
+                line: 5,
+                column: 5,
+                message: "Expect ')' after grouping expression. (found '2.')".into(),
+                severity: Severity::Error,
+                location: Some("'2.'".into()),
+                file: None,
+                section: Some("Steps".into()),
+            },
+            Diagnostic {
+                line: 5,
+                column: 14,
+                message: "Undefined variable.".into(),
+                severity: Severity::Error,
+                location: Some("'egg'".into()),
+                file: None,
+                section: Some("Steps".into()),
+            },
+        ]
+    );
+}
+
+#[test]
+fn compile_succeeds_without_diagnostics() {
+    let source = "Recipe\n\nSteps\n    1. taste 1 add 2\n    2. end\n";
+    assert!(compile(source).is_ok());
+}
+
+fn numbered_steps(count: usize, indent: &str, step: &str) -> String {
+    let mut steps = String::new();
+    for number in 1..=count {
+        steps.push_str(&format!("{indent}{number}. {step}\n"));
+    }
+    steps.push_str(&format!("{indent}{}. end\n", count + 1));
+    steps
+}
+
+#[test]
+fn constants_are_deduplicated_across_a_large_recipe() {
+    let mut body = String::new();
+    for number in 0..400 {
+        body.push_str(&format!("    {}. taste {}\n", number + 1, number % 200));
+    }
+    let source = format!("Recipe\n\nSteps\n{body}    401. end\n");
+    let code = compile(&source).expect("Source should compile.");
+    assert_eq!(code.constants_count, 200);
+    for (index, constant) in code.constants[..200].iter().enumerate() {
+        assert_eq!(*constant, Value::Number(index as f64));
+    }
+
+    let mut code = Code::new();
+    let list = Value::List(vec![Value::Number(1.0), Value::String("egg".into())]);
+    assert_eq!(code.add_constant(list.clone()), Ok(0));
+    assert_eq!(code.add_constant(Value::Number(f64::NAN)), Ok(1));
+    assert_eq!(code.add_constant(Value::Number(f64::NAN)), Ok(2));
+    assert_eq!(code.add_constant(list), Ok(0));
+}
+
+#[test]
+fn oversized_loop_body_is_a_compile_error() {
+    let body = numbered_steps(22_000, "        ", "taste 1");
+    let source = format!("Recipe\n\nSteps\n    1. while false\n{body}    2. end\n");
+    let diagnostics = compile(&source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Loop body too large.");
+    assert_eq!(diagnostics[0].line, 22_005);
+}
+
+#[test]
+fn oversized_jump_is_a_compile_error() {
+    let body = numbered_steps(22_000, "        ", "taste 1");
+    let source = format!("Recipe\n\nSteps\n    1. check not true\n{body}    2. end\n");
+    let diagnostics = compile(&source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Too much code to jump over.");
+    assert_eq!(diagnostics[0].line, 22_005);
+}
+
+#[test]
+fn crlf_is_normalised_in_multi_line_strings() {
+    let source = "Recipe\r\n\r\nSteps\r\n    1. taste \"first\r\nsecond\"\r\n    2. taste egg\r\n    3. end\r\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics[0].line, 6);
+    assert_eq!(diagnostics[0].column, 14);
+
+    let source = "Recipe\r\n\r\nSteps\r\n    1. taste \"first\r\nsecond\"\r\n    2. end\r\n";
+    let code = compile(source).expect("Source should compile.");
+    let constants = &code.constants[..code.constants_count];
+    assert!(constants.contains(&Value::String("first\nsecond".into())));
+}
+
+#[test]
+fn consume_errors_name_the_token_found() {
+    let source = "Recipe\n\nSteps\n    1. taste (1 add 2\n    2. end\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(
+        diagnostics[0].message,
+        "Expect ')' after grouping expression. (found '2.')"
+    );
+
+    let source =
+        "Recipe\n\nUtensils\nwhisk\n    1. serve 1\n\nSteps\n    1. taste whisk now\n    2. end\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(
+        diagnostics[0].message,
+        "Expect next or final instruction in the sequence. (found 'Steps')"
+    );
+}
+
+#[test]
+fn tabs_advance_the_column_to_the_next_tab_stop() {
+    let source = "Recipe\n\nSteps\n\t1. taste egg\n\t2. end\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics[0].column, 14);
+
+    let options = CompileOptions {
+        tab_width: 8,
+        ..CompileOptions::default()
+    };
+    let diagnostics =
+        compile_with_options(source, options).expect_err("Source should not compile.");
+    assert_eq!(diagnostics[0].column, 18);
+
+    let source = "Recipe\n\nSteps\n  \t1. taste egg\n\t2. end\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics[0].column, 14);
+}
+
+#[test]
+fn deeply_nested_expression_is_a_compile_error() {
+    let nesting = 10_000;
+    let expression = format!("{}1{}", "(".repeat(nesting), ")".repeat(nesting));
+    let source = format!("Recipe\n\nSteps\n    1. taste {expression}\n    2. end\n");
+    let diagnostics = compile(&source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Expression too deeply nested.");
+    assert_eq!(diagnostics[0].line, 4);
+}
+
+#[test]
+fn nested_expression_within_limit_compiles() {
+    let nesting = 200;
+    let expression = format!("{}1{}", "(".repeat(nesting), ")".repeat(nesting));
+    let source = format!("Recipe\n\nSteps\n    1. taste {expression}\n    2. end\n");
+    assert!(compile(&source).is_ok());
+}
+
+#[test]
+fn as_number_accepts_numbers_only() {
+    assert_eq!(Value::Number(1.5).as_number().unwrap(), 1.5);
+    let err = Value::String("1.5".into()).as_number().unwrap_err();
+    assert_eq!(err.to_string(), "Expected a number.");
+}
+
+#[test]
+fn truthy_is_false_only_for_false_and_nil() {
+    let source = "Recipe\n\nUtensils\nwhisk\n    1. serve 1\n    2. end\n\nSteps\n    1. end\n";
+    let code = compile(source).expect("Source should compile.");
+    let function = code.constants[..code.constants_count]
+        .iter()
+        .find(|constant| matches!(constant, Value::Function(_)))
+        .expect("Utensil should be a constant.");
+    let values = [
+        (Value::Nil, false),
+        (Value::Boolean(false), false),
+        (Value::Boolean(true), true),
+        (Value::Number(0.0), true),
+        (Value::String("".into()), true),
+        (Value::List(vec![]), true),
+        (function.clone(), true),
+        (Value::Unset("egg".into()), true),
+    ];
+    for (value, truthy) in values {
+        assert_eq!(value.truthy(), truthy, "{value:?}");
+        assert_eq!(value.falsey(), !truthy, "{value:?}");
+    }
+}
+
+#[test]
+fn as_string_accepts_strings_only() {
+    assert_eq!(Value::String("egg".into()).as_string().unwrap(), "egg");
+    let err = Value::Nil.as_string().unwrap_err();
+    assert_eq!(err.to_string(), "Expected a string.");
+}
+
+#[test]
+fn each_loop_cleans_up_with_a_single_pop_n() {
+    let source = "Recipe\n\nSteps\n    1. each x in [1, 2]\n        1. taste x\n        2. end\n    2. end\n";
+    let code = compile(source).expect("Source should compile.");
+    let instructions = instructions(&code);
+    let pop_n: Vec<_> = instructions.iter().filter(|i| i.contains("PopN")).collect();
+    assert_eq!(pop_n.len(), 1);
+    assert!(pop_n[0].ends_with("[count: 3]"));
+}
+
+#[test]
+fn shadowing_a_native_is_a_warning() {
+    let source = "Recipe\n\nUtensils\nwhisk\n    1. each product in [1]\n        1. taste product\n        2. end\n    2. end\n\nSteps\n    1. end\n";
+    let code = compile(source).expect("Warnings should not fail compilation.");
+    assert_eq!(
+        code.warnings,
+        vec![Diagnostic {
+            line: 5,
+            column: 13,
+            message: "'product' shadows the native utensil of the same name.".into(),
+            severity: Severity::Warning,
+            location: Some("'product'".into()),
+            file: None,
+            section: Some("Utensils".into()),
+        }]
+    );
+}
+
+#[test]
+fn ingredient_named_after_a_native_is_an_error() {
+    // Only the ingredient names can be declared, so 'time' can't be shadowed
+    let source =
+        "Recipe\n\nIngredients\nset time to 1\n\nSteps\n    1. taste time now\n    2. end\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 4] Error in Ingredients at 'time': Expect ingredient identifier name. (found 'time')"
+    );
+}
+
+#[test]
+fn line_directive_overrides_reported_lines() {
+    let source = "Recipe\n\nSteps\n//line 40 \"shared.recipe\"\n    1. taste egg\n    2. end\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].line, 40);
+    assert_eq!(diagnostics[0].file.as_deref(), Some("shared.recipe"));
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[shared.recipe, line 40] Error in Steps at 'egg': Undefined variable."
+    );
+}
+
+#[test]
+fn line_directive_without_file_returns_to_the_source() {
+    let source =
+        "Recipe\n\nSteps\n//line 7 \"shared.recipe\"\n//line 7\n    1. taste egg\n    2. end\n";
+    let diagnostics = compile(source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics[0].line, 7);
+    assert_eq!(diagnostics[0].file, None);
+}
+
+fn run_bytes(bytes: &[u8]) -> InterpretResult<()> {
+    run_bytes_traced(bytes, false)
+}
+
+fn run_bytes_traced(bytes: &[u8], trace: bool) -> InterpretResult<()> {
+    let mut code = Code::new();
+    for byte in bytes {
+        code.write(*byte, 1);
+    }
+    let mut state = State::new(code).with_trace(trace);
+    state.push_frame(CallFrame::default())?;
+    state.run()
+}
+
+#[test]
+fn loop_before_the_start_of_the_bytecode_is_out_of_bounds() {
+    let [low, high] = 10u16.to_le_bytes();
+    let result = run_bytes(&[Opcode::Loop as u8, low, high, Opcode::Return as u8]);
+    assert!(matches!(result, Err(ChefError::OutOfBounds)));
+}
+
+#[test]
+fn jump_past_the_end_of_the_bytecode_is_out_of_bounds() {
+    let [low, high] = 10u16.to_le_bytes();
+    let result = run_bytes(&[Opcode::Jump as u8, low, high, Opcode::Return as u8]);
+    assert!(matches!(result, Err(ChefError::OutOfBounds)));
+}
+
+#[test]
+fn jump_within_the_bytecode_runs() {
+    let [low, high] = 1u16.to_le_bytes();
+    let bytes = [
+        Opcode::Jump as u8,
+        low,
+        high,
+        Opcode::Pop as u8,
+        Opcode::Nil as u8,
+        Opcode::Return as u8,
+    ];
+    assert!(run_bytes(&bytes).is_ok());
+}
+
+#[test]
+fn literal_check_condition_drops_the_untaken_branch() {
+    let source = "Recipe\n\nSteps\n    1. check false\n        1. taste \"then\"\n        2. end\n    2. otherwise\n        1. taste \"else\"\n        2. end\n    3. end\n";
+    let code = compile(source).expect("Source should compile.");
+    let instructions = instructions(&code);
+    assert!(!instructions.iter().any(|i| i.contains("Jump")));
+    let constants: Vec<_> = instructions
+        .iter()
+        .filter(|i| i.contains("Constant"))
+        .collect();
+    assert_eq!(constants.len(), 1);
+    assert!(constants[0].ends_with("[constant: else]"));
+}
+
+#[test]
+fn untaken_branch_frees_its_constants() {
+    let dead_steps: String = (1..=255)
+        .map(|step| format!("        {step}. taste {step}\n"))
+        .collect();
+    let source = format!(
+        "Recipe\n\nSteps\n    1. check false\n{dead_steps}        256. end\n    2. taste \"kept\"\n    3. taste 1\n    4. end\n"
+    );
+    let code = compile(&source).expect("Source should compile.");
+    assert_eq!(code.constants_count, 2);
+    assert_eq!(code.constants[0], Value::String("kept".into()));
+    assert_eq!(code.constants[1], Value::Number(1.0));
+}
+
+const SERIALIZED_RECIPE: &str = "Recipe\n\nIngredients\nset egg\nset milk to [1, 2]\n\nUtensils\nwhisk with x\n    1. serve x add 1\n    2. end\n\nSteps\n    1. taste whisk with 2.5\n    2. taste \"done\"\n    3. end\n";
+
+#[test]
+fn serialized_bytecode_loads_at_the_current_version() {
+    let code = compile(SERIALIZED_RECIPE).expect("Source should compile.");
+    let loaded = Code::deserialize(&code.serialize()).expect("Bytecode should load.");
+    assert_eq!(loaded.bytes, code.bytes);
+    assert_eq!(loaded.lines, code.lines);
+    assert_eq!(
+        loaded.constants[..loaded.constants_count],
+        code.constants[..code.constants_count]
+    );
+}
+
+#[test]
+fn serialized_bytecode_with_another_version_is_rejected() {
+    let code = compile(SERIALIZED_RECIPE).expect("Source should compile.");
+    let mut bytes = code.serialize();
+    bytes[4] = BYTECODE_VERSION + 1;
+    let result = Code::deserialize(&bytes);
+    assert!(matches!(
+        result,
+        Err(ChefError::BytecodeVersion(version, BYTECODE_VERSION)) if version == BYTECODE_VERSION + 1
+    ));
+}
+
+#[test]
+fn truncated_bytecode_is_rejected() {
+    let code = compile(SERIALIZED_RECIPE).expect("Source should compile.");
+    let bytes = code.serialize();
+    let result = Code::deserialize(&bytes[..bytes.len() - 1]);
+    assert!(matches!(result, Err(ChefError::InvalidBytecode)));
+}
+
+#[test]
+fn whitespace_only_source_is_an_empty_recipe() {
+    let diagnostics = compile("  \n\t\n\n").expect_err("Source should not compile.");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 4] Error at end of file: Recipe is empty, expected it to begin with 'Recipe'."
+    );
+}
+
+#[test]
+fn call_with_more_arguments_than_the_stack_holds_underflows() {
+    let result = run_bytes(&[
+        Opcode::Nil as u8,
+        Opcode::Call as u8,
+        3,
+        Opcode::Return as u8,
+    ]);
+    assert!(matches!(result, Err(ChefError::StackUnderflow)));
+}
+
+#[test]
+fn same_local_on_both_sides_is_read_once() {
+    let source = "Recipe\n\nIngredients\nset egg to 3\n\nSteps\n    1. set egg to egg multiply egg\n    2. end\n";
+    let code = compile(source).expect("Source should compile.");
+    let instructions = instructions(&code);
+    let body: Vec<_> = instructions
+        .iter()
+        .skip_while(|i| !i.contains("GetLocal"))
+        .take(3)
+        .map(|i| i.split_whitespace().next().unwrap())
+        .collect();
+    assert_eq!(body, ["GetLocal", "Dup", "Multiply"]);
+}
+
+#[test]
+fn dup_on_an_empty_stack_underflows() {
+    let result = run_bytes(&[Opcode::Dup as u8, Opcode::Return as u8]);
+    assert!(matches!(result, Err(ChefError::StackUnderflow)));
+}
+
+#[test]
+fn pop_n_past_the_bottom_of_the_stack_underflows() {
+    let result = run_bytes(&[Opcode::PopN as u8, 3, Opcode::Return as u8]);
+    assert!(matches!(result, Err(ChefError::StackUnderflow)));
+}
+
+#[test]
+fn unknown_opcode_is_invalid_bytecode() {
+    let result = run_bytes(&[Opcode::COUNT, Opcode::Return as u8]);
+    assert!(matches!(result, Err(ChefError::InvalidBytecode)));
+}
+
+#[test]
+fn unknown_opcode_under_trace_is_invalid_bytecode() {
+    let result = run_bytes_traced(&[Opcode::COUNT, Opcode::Return as u8], true);
+    assert!(matches!(result, Err(ChefError::InvalidBytecode)));
+}
+
+#[test]
+fn truncated_operand_is_invalid_bytecode() {
+    for bytes in [
+        &[Opcode::Constant as u8][..],
+        &[Opcode::GetLocal as u8, 0],
+        &[Opcode::Jump as u8, 0],
+    ] {
+        assert!(matches!(run_bytes(bytes), Err(ChefError::InvalidBytecode)));
+        let result = run_bytes_traced(bytes, true);
+        assert!(matches!(result, Err(ChefError::InvalidBytecode)));
+    }
+}
+
+#[test]
+fn undecodable_instructions_disassemble_without_panicking() {
+    let mut code = Code::new();
+    for byte in [
+        Opcode::COUNT,
+        Opcode::Native as u8,
+        200,
+        Opcode::Loop as u8,
+        0,
+    ] {
+        code.write(byte, 1);
+    }
+    assert!(code
+        .disassemble_instruction(0)
+        .0
+        .ends_with("Unknown opcode 34"));
+    assert!(code
+        .disassemble_instruction(1)
+        .0
+        .ends_with("Native with invalid operands"));
+    assert!(code
+        .disassemble_instruction(3)
+        .0
+        .ends_with("Loop with invalid operands"));
+}
+
+#[test]
+fn nop_leaves_the_stack_untouched() {
+    // Return is only reached if the constant is still on top of the stack
+    let mut code = Code::new();
+    let three = code.add_constant(Value::Number(3.0)).unwrap();
+    let [low, high] = 1u16.to_le_bytes();
+    let bytes = [
+        Opcode::Constant as u8,
+        three,
+        Opcode::Nop as u8,
+        Opcode::Nop as u8,
+        Opcode::Constant as u8,
+        three,
+        Opcode::Equal as u8,
+        Opcode::JumpIfFalse as u8,
+        low,
+        high,
+        Opcode::Return as u8,
+        Opcode::Negate as u8,
+    ];
+    for byte in bytes {
+        code.write(byte, 1);
+    }
+    assert!(code.disassemble_instruction(2).0.ends_with("Nop"));
+    let mut state = State::new(code);
+    state.push_frame(CallFrame::default()).unwrap();
+    assert!(state.run().is_ok());
+}
+
+#[test]
+fn cloned_strings_share_their_text() {
+    let value = Value::String("self raising flour".into());
+    let (Value::String(original), Value::String(copy)) = (&value, value.clone()) else {
+        panic!("Cloning should keep the variant.");
+    };
+    assert!(Rc::ptr_eq(original, &copy));
+}
+
+fn hash_of(value: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn equal_values_hash_the_same() {
+    let pairs = [
+        (Value::Nil, Value::Nil),
+        (Value::Number(1.5), Value::Number(1.5)),
+        (Value::Number(0.0), Value::Number(-0.0)),
+        (Value::Boolean(true), Value::Boolean(true)),
+        (Value::String("egg".into()), Value::String("egg".into())),
+        (
+            Value::List(vec![Value::Number(1.0), Value::String("egg".into())]),
+            Value::List(vec![Value::Number(1.0), Value::String("egg".into())]),
+        ),
+        (Value::Unset("egg".into()), Value::Unset("egg".into())),
+    ];
+    for (a, b) in pairs {
+        assert!(a.is_equal(b.clone()));
+        assert_eq!(hash_of(&a), hash_of(&b), "{a} and {b} should hash the same");
+    }
+}
+
+#[test]
+fn functions_are_rejected_as_keys() {
+    let code = compile("Recipe\n\nUtensils\nwhisk\n    1. end\n\nSteps\n    1. end\n")
+        .expect("Source should compile.");
+    let function = code.constants[..code.constants_count]
+        .iter()
+        .find(|constant| constant.to_string() == "<fn whisk/0>")
+        .expect("Utensil should be a constant.");
+    assert!(matches!(function.as_key(), Err(ChefError::UnhashableKey)));
+    let list = Value::List(vec![Value::Number(1.0), function.clone()]);
+    assert!(matches!(list.as_key(), Err(ChefError::UnhashableKey)));
+    assert!(Value::String("egg".into()).as_key().is_ok());
+}
+
+#[test]
+fn deeply_nested_steps_are_a_compile_error() {
+    let mut source = String::from("Recipe\n\nSteps\n");
+    for _ in 0..10_000 {
+        source.push_str("1. check true\n");
+    }
+    let diagnostics = compile(&source).expect_err("Source should not compile.");
+    assert_eq!(diagnostics[0].message, "Steps too deeply nested.");
+}
+
+const FUZZ_WORDS: &[&str] = &[
+    "Recipe",
+    "Ingredients",
+    "Utensils",
+    "Steps",
+    "\n",
+    "    ",
+    "1.",
+    "2.",
+    "3.",
+    "egg",
+    "flour",
+    "whisk",
+    "bake",
+    "x",
+    "set",
+    "to",
+    "taste",
+    "check",
+    "otherwise",
+    "serve",
+    "each",
+    "in",
+    "end",
+    "add",
+    "minus",
+    "multiply",
+    "split",
+    "floor_split",
+    "now",
+    "with",
+    "and",
+    "compliments",
+    "or",
+    "not",
+    "is",
+    "above",
+    "below",
+    "true",
+    "false",
+    "nil",
+    "[",
+    "]",
+    "(",
+    ")",
+    ",",
+    "\"s\"",
+    "0",
+    "255",
+    "sum",
+    "split_on",
+    "trim",
+    "//line 3 \"f\"\n",
+    "é",
+    "#",
+];
+
+// Valid recipes cut off where a step begins, so the fuzzed text gets past
+// the headers and into the compiler and VM
+const FUZZ_PREFIXES: &[&str] = &[
+    "Recipe\n\nSteps\n    1. ",
+    "Recipe\n\nIngredients\nset egg to 1\nset flour to [1, 2]\n\nSteps\n    1. ",
+    "Recipe\n\nUtensils\nwhisk with x\n    1. serve x\n    2. end\n\nSteps\n    1. ",
+    "Recipe\n\nIngredients\nset egg to \"s\"\n\nUtensils\nbake\n    1. serve egg\n    2. end\n\nSteps\n    1. ",
+];
+
+#[test]
+fn try_run_does_not_panic_on_random_input() {
+    // A fixed linear congruential generator keeps failures reproducible
+    let mut seed: u64 = 0x5eed;
+    let mut next = move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) as usize
+    };
+    for round in 0..2_000 {
+        let prefix = FUZZ_PREFIXES[next() % FUZZ_PREFIXES.len()];
+        let body = match round % 2 {
+            0 => {
+                let bytes: Vec<u8> = (0..next() % 64).map(|_| next() as u8).collect();
+                String::from_utf8_lossy(&bytes).into_owned()
+            }
+            _ => {
+                let words: Vec<_> = (0..next() % 40)
+                    .map(|_| FUZZ_WORDS[next() % FUZZ_WORDS.len()])
+                    .collect();
+                words.join(" ")
+            }
+        };
+        let source = format!("{prefix}{body}\n    2. end\n");
+        // Any result is fine, only a panic fails the test
+        if let Err(RunError::Compile(diagnostics)) = try_run(&source) {
+            assert!(
+                !diagnostics.is_empty(),
+                "{source:?} failed without a diagnostic"
+            );
+        }
+    }
+}
+
+#[test]
+fn runtime_error_captures_a_backtrace() {
+    let source = "Recipe\n\nUtensils\ncook\n    1. serve 1 add \"egg\"\n    2. end\nbake\n    1. serve cook now\n    2. end\nwhisk\n    1. serve bake now\n    2. end\n\nSteps\n    1. taste whisk now\n    2. end\n";
+    let code = compile(source).expect("Source should compile.");
+    let mut state = State::new(code);
+    state.push_frame(CallFrame::default()).unwrap();
+    assert!(matches!(state.run(), Err(ChefError::ValueAddOperation)));
+    let frame = |name: &str, line| Frame {
+        name: name.into(),
+        line,
+    };
+    assert_eq!(
+        state.backtrace(),
+        vec![
+            frame("cook", 5),
+            frame("bake", 8),
+            frame("whisk", 11),
+            frame("", 15),
+        ]
+    );
+}
+
+#[test]
+fn diagnostics_name_the_section_they_occur_in() {
+    let cases = [
+        (
+            "Recipe\n\nIngredients\nset egg to milk\n\nSteps\n    1. end\n",
+            "[line 4] Error in Ingredients at 'milk': Undefined variable.",
+        ),
+        (
+            "Recipe\n\nUtensils\nwhisk\n    1. serve milk\n    2. end\n\nSteps\n    1. end\n",
+            "[line 5] Error in Utensils at 'milk': Undefined variable.",
+        ),
+        (
+            "Recipe\n\nSteps\n    1. taste milk\n    2. end\n",
+            "[line 4] Error in Steps at 'milk': Undefined variable.",
+        ),
+        (
+            "Recipe Recipe\n\nSteps\n    1. end\n",
+            "[line 1] Error at 'Recipe': Expect 'Recipe' to contain 'Steps' section (found 'Recipe')",
+        ),
+    ];
+    for (source, expected) in cases {
+        let diagnostics = compile(source).expect_err("Source should not compile.");
+        assert_eq!(diagnostics[0].to_string(), expected);
+    }
+}
+
+#[test]
+fn opcode_values_are_stable() {
+    let opcodes = [
+        (Opcode::Return, 0),
+        (Opcode::Negate, 1),
+        (Opcode::Add, 2),
+        (Opcode::Subtract, 3),
+        (Opcode::Multiply, 4),
+        (Opcode::Divide, 5),
+        (Opcode::FloorDivide, 6),
+        (Opcode::Nil, 7),
+        (Opcode::True, 8),
+        (Opcode::False, 9),
+        (Opcode::Not, 10),
+        (Opcode::Equal, 11),
+        (Opcode::Greater, 12),
+        (Opcode::Less, 13),
+        (Opcode::Print, 14),
+        (Opcode::Pop, 15),
+        (Opcode::GetLocal, 16),
+        (Opcode::SetLocal, 17),
+        (Opcode::Constant, 18),
+        (Opcode::JumpIfFalse, 19),
+        (Opcode::Jump, 20),
+        (Opcode::Loop, 21),
+        (Opcode::Call, 22),
+        (Opcode::List, 23),
+        (Opcode::Index, 24),
+        (Opcode::Length, 25),
+        (Opcode::PopN, 26),
+        (Opcode::Native, 27),
+        (Opcode::Dup, 28),
+        (Opcode::Nop, 29),
+        (Opcode::DebugPrint, 30),
+        (Opcode::PrettyPrint, 31),
+        (Opcode::In, 32),
+        (Opcode::JumpIfTrue, 33),
+    ];
+    assert_eq!(opcodes.len(), Opcode::COUNT as usize);
+    for (opcode, value) in opcodes {
+        assert_eq!(opcode as u8, value);
+    }
+}
+
+#[test]
+fn blocks_can_have_more_steps_than_fit_in_sixteen_bits() {
+    let mut steps: String = (1..=70_000)
+        .map(|number| format!("    {number}. set egg to egg add 1\n"))
+        .collect();
+    // Reading past the end of an empty list fails the run if a step was lost
+    steps.push_str("    70001. check egg isnt 70000\n        1. taste [][0]\n        2. end\n");
+    steps.push_str("    70002. end\n");
+    let source = format!("Recipe\n\nIngredients\nset egg to 0\n\nSteps\n{steps}");
+    assert!(try_run(&source).is_ok());
+}
+
+#[test]
+fn error_codes_are_unique_and_explained() {
+    // One of each variant in declaration order. The match fails to compile
+    // when a variant is added, so it can't be left out here
+    let errors = [
+        ChefError::Compile,
+        ChefError::OutOfBounds,
+        ChefError::StackOverflow,
+        ChefError::StackUnderflow,
+        ChefError::UndefinedVariable("egg".into()),
+        ChefError::InvalidCallee,
+        ChefError::FunctionArity(2, 1),
+        ChefError::ValueNegationOperation,
+        ChefError::ValueNumberOnlyOperation,
+        ChefError::ValueOrderOperation("string", "number"),
+        ChefError::ValueAddOperation,
+        ChefError::ValueListOperation,
+        ChefError::ListIndex,
+        ChefError::ExpectedNumber,
+        ChefError::ExpectedString,
+        ChefError::UnhashableKey,
+        ChefError::BytecodeVersion(0, 1),
+        ChefError::InvalidBytecode,
+        ChefError::OutputLimitExceeded(3),
+        ChefError::ValueInOperation("number", "string"),
+        ChefError::CapabilityRefused("Reading the clock", "in the sandbox"),
+    ];
+    for error in &errors {
+        match error {
+            ChefError::Compile
+            | ChefError::OutOfBounds
+            | ChefError::StackOverflow
+            | ChefError::StackUnderflow
+            | ChefError::UndefinedVariable(_)
+            | ChefError::InvalidCallee
+            | ChefError::FunctionArity(..)
+            | ChefError::ValueNegationOperation
+            | ChefError::ValueNumberOnlyOperation
+            | ChefError::ValueOrderOperation(..)
+            | ChefError::ValueAddOperation
+            | ChefError::ValueListOperation
+            | ChefError::ListIndex
+            | ChefError::ExpectedNumber
+            | ChefError::ExpectedString
+            | ChefError::UnhashableKey
+            | ChefError::BytecodeVersion(..)
+            | ChefError::InvalidBytecode
+            | ChefError::OutputLimitExceeded(_)
+            | ChefError::ValueInOperation(..)
+            | ChefError::CapabilityRefused(..) => {}
+        }
+    }
+    // Codes are numbered from E0001 in declaration order, so none repeat
+    for (index, error) in errors.iter().enumerate() {
+        assert_eq!(error.code(), format!("E{:04}", index + 1), "{error:?}");
+        assert!(
+            explain(error.code()).is_some(),
+            "{error:?} is not explained"
+        );
+    }
+    assert_eq!(explain(&format!("E{:04}", errors.len() + 1)), None);
+    assert_eq!(explain("e0003"), explain("E0003"));
+    assert_eq!(explain("E9999"), None);
+}
+
+#[test]
+fn about_section_is_attached_as_metadata() {
+    let source =
+        "Recipe\n\nAbout\nserves: 4\nsource: https://example.com/cake \n\nSteps\n    1. taste 1\n";
+    let code = compile(source).expect("Source should compile.");
+    assert_eq!(code.metadata_value("serves"), Some("4"));
+    assert_eq!(
+        code.metadata_value("source"),
+        Some("https://example.com/cake")
+    );
+    assert_eq!(code.metadata_value("allergens"), None);
+}
+
+#[test]
+fn dump_ast_follows_operator_precedence() {
+    let source = "Recipe\n\nIngredients\nset egg to 2\n\nSteps\n    1. taste 1 add egg multiply 3\n    2. set egg to (1 add 2) split egg\n    3. taste minus egg\n";
+    assert_eq!(
+        dump_ast(source),
+        "(recipe
+  (ingredients
+    (set egg 2))
+  (steps
+    (taste (add 1 (multiply egg 3)))
+    (set egg (split (add 1 2) egg))
+    (taste (minus egg))))"
+    );
+}
+
+#[test]
+fn keywords_lists_reserved_words_only() {
+    let keywords = keywords();
+    for keyword in ["set", "taste", "check", "Recipe", "mix_while", "then"] {
+        assert!(keywords.contains(&keyword), "missing '{keyword}'");
+    }
+    for identifier in ["egg", "whisk", "sum"] {
+        assert!(
+            !keywords.contains(&identifier),
+            "'{identifier}' isn't reserved"
+        );
+    }
+}
+
+fn optimized(bytes: &[u8]) -> Code {
+    let mut code = Code::new();
+    for (line, byte) in bytes.iter().enumerate() {
+        code.write(*byte, line + 1);
+    }
+    optimize(&mut code);
+    code
+}
+
+// Each instruction without its offset and line prefix
+fn instructions(code: &Code) -> Vec<String> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < code.bytes.len() {
+        let (instruction, next_offset) = code.disassemble_instruction(offset);
+        instructions.push(instruction[16..].to_owned());
+        offset = next_offset;
+    }
+    instructions
+}
+
+#[test]
+fn or_short_circuits_with_one_conditional_jump() {
+    let source =
+        "Recipe\n\nIngredients\nset egg to false\n\nSteps\n    1. taste egg or 2\n    2. end\n";
+    let code = compile(source).expect("Source should compile.");
+    let instructions = instructions(&code);
+    let or_start = instructions
+        .iter()
+        .position(|instruction| instruction.starts_with("GetLocal"))
+        .unwrap();
+    let expected = [
+        "JumpIfTrue     [offset: 3]",
+        "Pop",
+        "Constant       [constant: 2]",
+        "Print",
+    ];
+    assert_eq!(instructions[or_start + 1..or_start + 5], expected);
+}
+
+#[test]
+fn optimize_negates_a_literal_boolean() {
+    let code = optimized(&[
+        Opcode::True as u8,
+        Opcode::Not as u8,
+        Opcode::False as u8,
+        Opcode::Not as u8,
+        Opcode::Return as u8,
+    ]);
+    assert_eq!(instructions(&code), ["False", "True", "Return"]);
+    assert_eq!(code.lines, [1, 3, 5]);
+}
+
+#[test]
+fn optimize_collapses_pops_and_removes_nops() {
+    let code = optimized(&[
+        Opcode::Nil as u8,
+        Opcode::Nil as u8,
+        Opcode::Nil as u8,
+        Opcode::Nop as u8,
+        Opcode::Pop as u8,
+        Opcode::Pop as u8,
+        Opcode::Pop as u8,
+        Opcode::Nop as u8,
+        Opcode::Return as u8,
+    ]);
+    let expected = ["Nil", "Nil", "Nil", "PopN           [count: 3]", "Return"];
+    assert_eq!(instructions(&code), expected);
+}
+
+#[test]
+fn optimize_keeps_jumps_landing_on_their_instruction() {
+    // The jump skips a Nop to land on the second Pop, so those Pops can't merge
+    let [low, high] = 2u16.to_le_bytes();
+    let code = optimized(&[
+        Opcode::Nil as u8,
+        Opcode::Nil as u8,
+        Opcode::False as u8,
+        Opcode::JumpIfFalse as u8,
+        low,
+        high,
+        Opcode::Nop as u8,
+        Opcode::Pop as u8,
+        Opcode::Pop as u8,
+        Opcode::Pop as u8,
+        Opcode::Return as u8,
+    ]);
+    let expected = [
+        "Nil",
+        "Nil",
+        "False",
+        "JumpIfFalse    [offset: 1]",
+        "Pop",
+        "PopN           [count: 2]",
+        "Return",
+    ];
+    assert_eq!(instructions(&code), expected);
+    let mut state = State::new(code);
+    state.push_frame(CallFrame::default()).unwrap();
+    assert!(state.run().is_ok());
+}
+
+#[test]
+fn optimize_leaves_undecodable_code_alone() {
+    let [low, high] = 10u16.to_le_bytes();
+    for bytes in [
+        &[Opcode::Nop as u8, Opcode::COUNT, Opcode::Return as u8][..],
+        &[Opcode::Nop as u8, Opcode::Constant as u8],
+        &[Opcode::Nop as u8, Opcode::Loop as u8, low, high],
+    ] {
+        assert_eq!(optimized(bytes).bytes, bytes);
+    }
+}
+
+#[test]
+fn optimize_keeps_moved_utensils_findable_as_constants() {
+    let source = "Recipe\n\nIngredients\nset egg to not true\n\nUtensils\nwhisk\n    1. serve egg\n    2. end\n\nSteps\n    1. taste whisk now\n    2. end\n";
+    let mut code = compile(source).expect("Source should compile.");
+    let whisk = code.constants[..code.constants_count]
+        .iter()
+        .position(|constant| matches!(constant, Value::Function(_)))
+        .unwrap();
+    let before = code.constants[whisk].clone();
+    optimize(&mut code);
+    assert_ne!(
+        code.constants[whisk], before,
+        "The utensil should have moved"
+    );
+    let count = code.constants_count;
+    let index = code.add_constant(code.constants[whisk].clone()).unwrap();
+    assert_eq!((index as usize, code.constants_count), (whisk, count));
+}
+
+#[test]
+fn optimize_moves_utensil_entry_points() {
+    let source = "Recipe\n\nUtensils\nwhisk with x\n    1. serve x add 1\n    2. end\n\nSteps\n    1. taste whisk with 2\n    2. end\n";
+    let mut code = compile(source).expect("Source should compile.");
+    // Pad the start of the code so everything after it moves
+    code.bytes.insert(0, Opcode::Nop as u8);
+    code.lines.insert(0, 1);
+    for constant in &mut code.constants[..code.constants_count] {
+        if let Value::Function(function) = constant {
+            function.ip_start += 1;
+        }
+    }
+    optimize(&mut code);
+    assert_eq!(code.bytes[0], Opcode::Native as u8);
+    let mut state = State::new(code);
+    state.push_frame(CallFrame::default()).unwrap();
+    assert!(state.run().is_ok());
+}
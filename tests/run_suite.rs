@@ -16,6 +16,7 @@ struct RuntimeError {
 struct Expected {
     out: Vec<String>,
     compile_err: Vec<String>,
+    compile_warn: Vec<String>,
     runtime_err: Option<RuntimeError>,
 }
 
@@ -48,10 +49,13 @@ fn parse_comments(path: &PathBuf) -> Expected {
     let error_re = Regex::new(r"// (Error.*)").expect("Invalid regex.");
     let error_line_re = Regex::new(r"// \[(?:c )?line (\d+)\] (Error.*)").expect("Invalid regex.");
     let runtime_error_re = Regex::new(r"// expect runtime error: (.+)").expect("Invalid regex.");
+    let warning_re = Regex::new(r"// (Warning.*)").expect("Invalid regex.");
+    let warning_line_re = Regex::new(r"// \[line (\d+)\] (Warning.*)").expect("Invalid regex.");
 
     let mut expected = Expected {
         out: vec![],
         compile_err: vec![],
+        compile_warn: vec![],
         runtime_err: None,
     };
 
@@ -73,6 +77,15 @@ fn parse_comments(path: &PathBuf) -> Expected {
             let s = format!("[line {}] {}", i + 1, msg);
             expected.compile_err.push(s);
         }
+        if let Some(m) = warning_line_re.captures(line) {
+            let s = format!("[line {}] {}", &m[1], &m[2]);
+            expected.compile_warn.push(s);
+        }
+        if let Some(m) = warning_re.captures(line) {
+            let msg = &m[1];
+            let s = format!("[line {}] {}", i + 1, msg);
+            expected.compile_warn.push(s);
+        }
         if let Some(m) = runtime_error_re.captures(line) {
             let message = m[1].to_owned();
             let line_prefix = format!("[line {}]", i + 1);
@@ -86,6 +99,12 @@ fn parse_comments(path: &PathBuf) -> Expected {
 }
 
 fn run_assertions(expected: Expected, output: Output, out: Vec<String>, err: Vec<String>) {
+    // Warnings are reported on stderr but never change the exit code
+    let warning_re = Regex::new(r"^\[line \d+\] Warning").expect("Invalid regex.");
+    let (warn, err): (Vec<String>, Vec<String>) =
+        err.into_iter().partition(|line| warning_re.is_match(line));
+    assert_eq!(expected.compile_warn, warn, "Compile warnings should match");
+
     match (
         expected.runtime_err.is_none(),
         expected.compile_err.is_empty(),
@@ -113,8 +132,13 @@ fn run_assertions(expected: Expected, output: Output, out: Vec<String>, err: Vec
         (false, false) => panic!("Simultaneous error and compile error"),
     }
 
+    // Errors are headed by their code, which the fixtures leave out
+    let code_re = Regex::new(r"^error\[E\d{4}\]: (.*)").expect("Invalid regex.");
     if let Some(e) = expected.runtime_err {
-        assert_eq!(e.message, err[0], "Runtime error should match");
+        let message = code_re
+            .captures(&err[0])
+            .expect("Runtime error should have a code");
+        assert_eq!(e.message, &message[1], "Runtime error should match");
         assert_eq!(
             err[1][0..e.line_prefix.len()],
             e.line_prefix,
@@ -131,6 +155,14 @@ fn run_assertions(expected: Expected, output: Output, out: Vec<String>, err: Vec
                 "Compile errors should have error code 65"
             );
         }
+        let mut err = err;
+        if !expected.compile_err.is_empty() {
+            assert_eq!(
+                err.pop().as_deref(),
+                Some("error[E0001]: Could not compile."),
+                "Compile errors should end with their code"
+            );
+        }
         assert_eq!(expected.compile_err, err, "Compile error should match");
     }
 